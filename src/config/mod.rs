@@ -3,7 +3,15 @@
 use ::std::io;
 use ::std::process;
 use ::std::fs;
+use ::std::sync::Mutex;
+use ::std::thread;
+use ::std::time::{Duration, Instant};
 use crate::diskio::read_write;
+use crate::storage::compact;
+use crate::storage::journal::Journal;
+use crate::storage::keys::KeyAllocator;
+use crate::storage::volume::VolumeSet;
+use crate::storage::{IndexFile, IndexFileItem};
 
 #[derive(Debug)]
 pub struct Config {
@@ -15,10 +23,28 @@ pub struct Config {
   pub config_port: u32,   // listen at for reload, stop
   pub service_port: u32,  // listen at for serve
 
-  pub volume_name: String, // the physical filename
+  pub volume_name: String, // the physical filename prefix, volumes are named volume_name.0, volume_name.1, ...
   pub index_name: String,  // the index filename
+  pub key_file: String,    // where the high-water mark of keys store
+  pub journal_name: String, // the journal of index changes since the last checkpoint
+
+  pub checkpoint_every: usize, // fold the journal into the index file after this many changes
+
+  pub max_volume_size: u64,  // the maxinum size(bytes) of one volume before a new one is used
+
+  pub max_chunked_body: u64, // the maxinum size(bytes) of a body without Content-Length, it is collected in memory
 
   pub max_index_in_mem: u64, // the maxinum memory(bytes) can be used to storing index
+
+  pub keep_versions: u32,    // older versions of a file kept by updates, 0 to keep none
+}
+
+/// the service is gone, the next ``start`` writes its pid again
+pub fn remove_pid_file(pid_file: &str) -> io::Result<()> {
+  match fs::remove_file(pid_file) {
+    Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+    _ => Ok(())
+  }
 }
 
 impl Config {
@@ -35,12 +61,23 @@ impl Config {
 
       volume_name: "heystack.volume".to_string(),
       index_name: "heystack.index".to_string(),
+      key_file: "heystack.key".to_string(),
+      journal_name: "heystack.journal".to_string(),
+
+      checkpoint_every: 10000,
+
+      max_volume_size: 4 * 1024 * 1024 * 1024, // 4 Gb
+
+      max_chunked_body: 16 * 1024 * 1024, // 16 Mb
 
       max_index_in_mem: 1024 * 1024 * 1024, // 1024 Mb
+
+      keep_versions: 0,
     };
 
     c.get_pid_from_file()?;
     c.create_files()?;
+    c.volumes()?;
     Ok(c)
   }
 
@@ -75,13 +112,12 @@ impl Config {
   fn create_files(&self) -> io::Result<()> {
     let filenames: Vec::<&str> = vec![
       &self.pid_file,
-      &self.volume_name,
       &self.index_name
     ];
 
     for filename in filenames {
       // test file if is exists
-      if let Err(_) = fs::File::open(filename) {
+      if fs::File::open(filename).is_err() {
         // that file isn't exists
         // try to create it, but write nothing
         fs::File::create(filename)?;
@@ -101,6 +137,26 @@ impl Config {
     stdout.contains(&::std::env::args().collect::<Vec<String>>()[0])
   }
 
+  /// the indexes that fit into max_index_in_mem
+  pub fn max_indexes(&self) -> usize {
+    (self.max_index_in_mem / std::mem::size_of::<IndexFileItem>() as u64) as usize
+  }
+
+  /// all volumes of the store, the first one is created if there is none
+  pub fn volumes(&self) -> io::Result<VolumeSet> {
+    VolumeSet::open(&self.volume_name, self.max_volume_size)
+  }
+
+  /// the allocator of keys for new files
+  pub fn keys(&self) -> io::Result<KeyAllocator> {
+    KeyAllocator::open(&self.key_file)
+  }
+
+  /// the journal of the index, opened for appending
+  pub fn journal(&self) -> io::Result<Journal> {
+    Journal::open(&self.journal_name, self.checkpoint_every)
+  }
+
   // to test service is started
   pub fn is_started(&self) -> bool {
     self.tpid != 0
@@ -116,15 +172,46 @@ impl Config {
     read_write::modify_struct_in_file(&self.tpid, &mut f)
   }
 
-  pub fn reload_index_file(&mut self) -> io::Result<()> {
-    let mut f = fs::File::open(&self.volume_name)?;
-    let r = crate::storage::PhysicalFileItem::build_index_file(&mut f)?;
+  /// ask the running service to stop and wait until it has flushed everything and removed the pid file
+  /// return false if it is still stopping after timeout
+  pub fn stop_service(&mut self, timeout: Duration) -> io::Result<bool> {
+    let status = process::Command::new("kill")
+      .arg("-TERM")
+      .arg(self.tpid.to_string())
+      .status()?;
+    if !status.success() {
+      return Err(io::Error::new(io::ErrorKind::Other, format!("cannot signal pid {}", self.tpid)));
+    }
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+      thread::sleep(Duration::from_millis(100));
+      if fs::metadata(&self.pid_file).is_err() {
+        self.tpid = 0;
+        return Ok(true);
+      }
+    }
+    Ok(false)
+  }
 
-    // remove the current index file
-    fs::remove_file(&self.index_name)?;
-    // write to file
+  pub fn reload_index_file(&mut self) -> io::Result<()> {
+    IndexFile::rebuild(&self.index_name, &self.volumes()?, &self.journal_name, self.max_indexes())
+  }
 
-    crate::storage::IndexFile::create_index_file_and_save(&self.index_name, r)?;
+  /// compact the volume while the service is not running
+  pub fn compact_volume(&mut self) -> io::Result<()> {
+    let indexes = crate::master::load_index_file(self)?;
+    let index_file = Mutex::new(IndexFile::new(
+      indexes,
+      self.max_indexes(),
+      self.keep_versions,
+      self.index_name.clone(),
+      self.volumes()?,
+      self.keys()?,
+      self.journal()?
+    )?);
+    for report in compact::compact(&index_file)? {
+      crate::loglnf!(report);
+    }
 
     Ok(())
   }
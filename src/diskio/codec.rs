@@ -0,0 +1,151 @@
+//! the on-disk byte layout of every record
+//! all integers are little-endian, bool is one byte (0 or 1), no padding
+
+use ::std::io;
+
+/// a value with a fixed-size, architecture independent encoding
+pub trait Codec: Sized {
+  /// number of bytes `encode` writes
+  const SIZE: usize;
+
+  /// append exactly `Self::SIZE` bytes to buf
+  fn encode(&self, buf: &mut Vec<u8>);
+
+  /// decode from exactly `Self::SIZE` bytes
+  fn decode(buf: &[u8]) -> io::Result<Self>;
+
+  fn to_bytes(&self) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(Self::SIZE);
+    self.encode(&mut buf);
+    buf
+  }
+}
+
+/// read the fields of a composite record one after another
+pub struct Reader<'a> {
+  buf: &'a [u8]
+}
+
+impl<'a> Reader<'a> {
+  pub fn new(buf: &'a [u8]) -> Self {
+    Reader { buf }
+  }
+
+  pub fn read<T: Codec>(&mut self) -> io::Result<T> {
+    if self.buf.len() < T::SIZE {
+      return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "record is too short"));
+    }
+    let (head, tail) = self.buf.split_at(T::SIZE);
+    self.buf = tail;
+    T::decode(head)
+  }
+
+  /// the next len bytes as they are
+  pub fn read_bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+    if self.buf.len() < len {
+      return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "record is too short"));
+    }
+    let (head, tail) = self.buf.split_at(len);
+    self.buf = tail;
+    Ok(head)
+  }
+
+  /// the bytes not read yet
+  pub fn left(&self) -> usize {
+    self.buf.len()
+  }
+}
+
+fn check_len<T: Codec>(buf: &[u8]) -> io::Result<()> {
+  if buf.len() != T::SIZE {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidData,
+      format!("expect {} bytes, got {}", T::SIZE, buf.len())
+    ));
+  }
+  Ok(())
+}
+
+macro_rules! impl_codec_for_int {
+  ( $( $t:ty ),* ) => {
+    $(
+      impl Codec for $t {
+        const SIZE: usize = ::std::mem::size_of::<$t>();
+
+        fn encode(&self, buf: &mut Vec<u8>) {
+          buf.extend_from_slice(&self.to_le_bytes());
+        }
+
+        fn decode(buf: &[u8]) -> io::Result<Self> {
+          check_len::<Self>(buf)?;
+          let mut bytes = [0u8; ::std::mem::size_of::<$t>()];
+          bytes.copy_from_slice(buf);
+          Ok(<$t>::from_le_bytes(bytes))
+        }
+      }
+    )*
+  };
+}
+
+impl_codec_for_int!(u8, u16, u32, u64);
+
+impl Codec for bool {
+  const SIZE: usize = 1;
+
+  fn encode(&self, buf: &mut Vec<u8>) {
+    buf.push(*self as u8);
+  }
+
+  fn decode(buf: &[u8]) -> io::Result<Self> {
+    check_len::<Self>(buf)?;
+    match buf[0] {
+      0 => Ok(false),
+      1 => Ok(true),
+      b => Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid bool byte {}", b)))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn integers_are_little_endian() {
+    assert_eq!(0x12u8.to_bytes(), vec![0x12]);
+    assert_eq!(0x1234u16.to_bytes(), vec![0x34, 0x12]);
+    assert_eq!(0x12345678u32.to_bytes(), vec![0x78, 0x56, 0x34, 0x12]);
+    assert_eq!(
+      0x0102030405060708u64.to_bytes(),
+      vec![0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]
+    );
+
+    assert_eq!(u32::decode(&[0x78, 0x56, 0x34, 0x12]).unwrap(), 0x12345678);
+    assert_eq!(u64::decode(&[1, 0, 0, 0, 0, 0, 0, 0x80]).unwrap(), 0x8000000000000001);
+  }
+
+  #[test]
+  fn bool_is_one_byte() {
+    assert_eq!(true.to_bytes(), vec![1]);
+    assert_eq!(false.to_bytes(), vec![0]);
+    assert!(bool::decode(&[1]).unwrap());
+    assert!(!bool::decode(&[0]).unwrap());
+    assert!(bool::decode(&[2]).is_err());
+  }
+
+  #[test]
+  fn wrong_length_is_rejected() {
+    assert!(u32::decode(&[1, 2, 3]).is_err());
+    assert!(u16::decode(&[1, 2, 3]).is_err());
+  }
+
+  #[test]
+  fn reader_reads_fields_in_order() {
+    let bytes = [0x01, 0x02, 0x00, 0x01, 0xff];
+    let mut reader = Reader::new(&bytes);
+    assert_eq!(reader.read::<u8>().unwrap(), 1);
+    assert_eq!(reader.read::<u16>().unwrap(), 2);
+    assert!(reader.read::<bool>().unwrap());
+    assert!(reader.read::<u32>().is_err());
+  }
+}
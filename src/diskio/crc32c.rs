@@ -0,0 +1,56 @@
+//! CRC-32C (Castagnoli), used to detect corrupted records on disk
+
+const POLY: u32 = 0x82f6_3b78; // reversed 0x1EDC6F41
+
+const TABLE: [u32; 256] = make_table();
+
+const fn make_table() -> [u32; 256] {
+  let mut table = [0u32; 256];
+  let mut i = 0;
+  while i < 256 {
+    let mut crc = i as u32;
+    let mut bit = 0;
+    while bit < 8 {
+      crc = if crc & 1 == 1 { (crc >> 1) ^ POLY } else { crc >> 1 };
+      bit += 1;
+    }
+    table[i] = crc;
+    i += 1;
+  }
+  table
+}
+
+/// continue a checksum with more bytes
+/// start with ``update(0, ..)``
+pub fn update(crc: u32, bytes: &[u8]) -> u32 {
+  let mut crc = !crc;
+  for b in bytes {
+    crc = TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8);
+  }
+  !crc
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn checksum(bytes: &[u8]) -> u32 {
+    update(0, bytes)
+  }
+
+  #[test]
+  fn known_values() {
+    // test vectors from RFC 3720, B.4
+    assert_eq!(checksum(b""), 0);
+    assert_eq!(checksum(b"123456789"), 0xe306_9283);
+    assert_eq!(checksum(&[0u8; 32]), 0x8a91_36aa);
+    assert_eq!(checksum(&[0xffu8; 32]), 0x62a8_ab43);
+  }
+
+  #[test]
+  fn update_in_pieces() {
+    let data = b"The quick brown fox jumps over the lazy dog";
+    let (a, b) = data.split_at(10);
+    assert_eq!(update(update(0, a), b), checksum(data));
+  }
+}
@@ -1,2 +1,3 @@
-pub mod struct_slice;
+pub mod codec;
+pub mod crc32c;
 pub mod read_write;
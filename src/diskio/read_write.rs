@@ -1,40 +1,44 @@
 //! write a struct into ::std::fs::File (append)
 //! read struct(s) from ::std::fs::File
 //! help change a sturct of ::std::fs::File
+//!
+//! structs are stored with their ``Codec`` layout, never as raw memory
 
 use ::std::io;
 use ::std::io::prelude::*;
 use ::std::fs;
-use ::std::mem;
 
-use super::struct_slice;
+use super::codec::Codec;
 
 /// OpenOption: append
-pub fn append_struct_to_file<T: Sized>(s: &T, f: &mut fs::File) -> io::Result<()> {
-  let slice = unsafe { struct_slice::struct_into_slice(s) };
-  f.write_all(slice)?;
+pub fn append_struct_to_file<T: Codec>(s: &T, f: &mut fs::File) -> io::Result<()> {
+  f.write_all(&s.to_bytes())?;
 
   Ok(())
 }
 
 /// read **one** struct from file
-pub fn read_struct_from_file<T: Sized>(f: &mut fs::File) -> io::Result<Option<T>> {
-  let size = mem::size_of::<T>();
-  let mut vec = vec![0u8; size];
-  match f.read(vec.as_mut_slice())? {
-    0 => Ok(None),
-    _ => {
-      let s = unsafe { struct_slice::slice_info_struct(&vec[..])? };
-      Ok(Some(s))
+/// return Ok(None) if the file is at its end
+pub fn read_struct_from_file<T: Codec>(f: &mut fs::File) -> io::Result<Option<T>> {
+  let mut vec = vec![0u8; T::SIZE];
+  let mut filled = 0;
+  while filled < T::SIZE {
+    match f.read(&mut vec[filled..])? {
+      0 => break,
+      n => filled += n
     }
   }
+  match filled {
+    0 => Ok(None),
+    n if n == T::SIZE => Ok(Some(T::decode(&vec)?)),
+    _ => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "struct is truncated"))
+  }
 }
 
 /// OpenOption: write
 /// modify **one** struct in file
-pub fn modify_struct_in_file<T: Sized>(s: &T, f: &mut fs::File) -> io::Result<()> {
-  let slice = unsafe { struct_slice::struct_into_slice(s) };
-  f.write_all(slice)?;
+pub fn modify_struct_in_file<T: Codec>(s: &T, f: &mut fs::File) -> io::Result<()> {
+  f.write_all(&s.to_bytes())?;
 
   Ok(())
 }
@@ -42,18 +46,18 @@ pub fn modify_struct_in_file<T: Sized>(s: &T, f: &mut fs::File) -> io::Result<()
 /// read some bytes from file
 pub fn read_bytes_from_file(size: u64, f: &mut fs::File) -> io::Result<Vec::<u8>> {
   let mut r: Vec::<u8> = vec![0u8; size as usize];
-  let mut reader = io::BufReader::new(f);
-  reader.read_exact(&mut r)?;
+  f.read_exact(&mut r)?;
   Ok(r)
 }
 
-pub fn write_bytes_to_file(bytes: &Vec::<u8>, f: &mut fs::File) -> io::Result<()> {
-  f.write_all(&bytes[..])
+pub fn write_bytes_to_file(bytes: &[u8], f: &mut fs::File) -> io::Result<()> {
+  f.write_all(bytes)
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use super::super::codec::Reader;
 
   #[derive(Debug, Clone, Copy)]
   struct TestStruct {
@@ -61,9 +65,23 @@ mod tests {
     pub b: u8
   }
 
+  impl Codec for TestStruct {
+    const SIZE: usize = 2;
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+      self.a.encode(buf);
+      self.b.encode(buf);
+    }
+
+    fn decode(buf: &[u8]) -> io::Result<Self> {
+      let mut reader = Reader::new(buf);
+      Ok(TestStruct { a: reader.read()?, b: reader.read()? })
+    }
+  }
+
   impl std::cmp::PartialEq for TestStruct {
     fn eq(&self, other: &Self) -> bool {
-      return self.a == other.a && self.b == other.b;
+      self.a == other.a && self.b == other.b
     }
   }
 
@@ -84,7 +102,7 @@ mod tests {
     }
 
     {
-      let mut f = fs::OpenOptions::new().write(true).append(true).open(filename)?;
+      let mut f = fs::OpenOptions::new().append(true).open(filename)?;
       append_struct_to_file(&b, &mut f)?;
     }
 
@@ -98,8 +116,7 @@ mod tests {
 
     {
       let mut f = fs::File::open(filename)?;
-      let size = mem::size_of::<TestStruct>();
-      f.seek(io::SeekFrom::Start(size as u64))?;
+      f.seek(io::SeekFrom::Start(TestStruct::SIZE as u64))?;
       let s: Option<TestStruct> = read_struct_from_file(&mut f)?;
       assert_eq!(s, Some(b));
     }
@@ -164,6 +181,23 @@ mod tests {
     }
   }
 
+  impl Codec for TestStruct2 {
+    const SIZE: usize = 1 + 2 + 1 + 1 + 8;
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+      self.a.encode(buf);
+      self.b.encode(buf);
+      self.c.encode(buf);
+      self.d.encode(buf);
+      self.e.encode(buf);
+    }
+
+    fn decode(buf: &[u8]) -> io::Result<Self> {
+      let mut reader = Reader::new(buf);
+      Ok(TestStruct2::new(reader.read()?, reader.read()?, reader.read()?, reader.read()?, reader.read()?))
+    }
+  }
+
   impl std::cmp::PartialEq for TestStruct2 {
     fn eq(&self, other: &Self) -> bool {
       self.a == other.a && self.b == other.b && self.c == other.c && self.d == other.d && self.e == other.e
@@ -204,4 +238,29 @@ mod tests {
     fs::remove_file(filename)?;
     Ok(())
   }
+
+  #[test]
+  fn file_matches_fixture_bytes() -> io::Result<()> {
+    let a = TestStruct2::new(1, 0x0102, true, 7, 0x1122334455667788);
+    let filename = "test444";
+    {
+      let mut f = fs::File::create(filename)?;
+      append_struct_to_file(&a, &mut f)?;
+    }
+    assert_eq!(
+      fs::read(filename)?,
+      vec![0x01, 0x02, 0x01, 0x01, 0x07, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11]
+    );
+
+    {
+      // a truncated struct is an error rather than garbage
+      let f = fs::OpenOptions::new().write(true).open(filename)?;
+      f.set_len(5)?;
+      let mut f = fs::File::open(filename)?;
+      assert!(read_struct_from_file::<TestStruct2>(&mut f).is_err());
+    }
+
+    fs::remove_file(filename)?;
+    Ok(())
+  }
 }
\ No newline at end of file
@@ -7,6 +7,7 @@ pub struct Options {
   pub reload: bool,   // reload service
   pub show: bool,     // show the basic config files, basic env, etc
   pub stop: bool,     // stop service
+  pub compact: bool,  // compact the volume
   pub unknown: Vec<String>, // unknown options
   pub empty: bool     // no option
 }
@@ -19,6 +20,7 @@ impl Options {
       reload: false,
       show: false,
       stop: false,
+      compact: false,
       unknown: Vec::new(),
       empty: false
     }
@@ -30,7 +32,7 @@ pub fn get_options() -> Options {
   args.remove(0); // remove the program name
 
   let mut options = Options::new();
-  if args.len() == 0 {
+  if args.is_empty() {
     options.empty = true;
   }
 
@@ -41,6 +43,7 @@ pub fn get_options() -> Options {
       "r" | "reload" => options.reload = true,
       "show" => options.show = true,
       "stop" => options.stop = true,
+      "compact" => options.compact = true,
       _ => options.unknown.push(arg)
     }
   }
@@ -1,11 +1,15 @@
+use ::std::fs;
 use ::std::io;
+use ::std::time::Duration;
 
 use super::options;
 use crate::config::Config;
 use crate::master;
+use crate::storage::volume::Superblock;
+use crate::storage::Stats;
 
 pub fn deal_with_options(option: &options::Options) -> io::Result<()> {
-  if option.unknown.len() != 0 {
+  if !option.unknown.is_empty() {
     crate::log!("Unknown option(s): ");
     for op in &option.unknown {
       crate::log!(op);
@@ -31,17 +35,40 @@ pub fn deal_with_options(option: &options::Options) -> io::Result<()> {
 
     Ok(())
   } else if option.stop {
-    unimplemented!("stop is ub");
+    let mut config = Config::new()?;
+    if !config.is_started() {
+      crate::logln!("The service is not started");
+      panic!("");
+    }
+    crate::logln!("Stopping the service at pid ", config.tpid);
+    // the requests in flight may take up to the shutdown timeout of the server
+    if config.stop_service(Duration::from_secs(60))? {
+      crate::logln!("Stopped");
+    } else {
+      crate::logln!("The service is still stopping, see its output");
+    }
+
+    Ok(())
   } else if option.reload {
     let mut config = Config::new()?;
     if config.is_started() {
       crate::logln!("The service is started at pid ", config.tpid);
       crate::logln!("Cannot reload index file if service is already started");
-      crate::logln!("Try run 'stop' and retry");
+      crate::logln!("Try POST /reload on the config port, or run 'stop' and retry");
       panic!("");
     }
     config.reload_index_file()?;
 
+    Ok(())
+  } else if option.compact {
+    let mut config = Config::new()?;
+    if config.is_started() {
+      crate::logln!("The service is started at pid ", config.tpid);
+      crate::logln!("Try POST /compact on the config port to compact the running service");
+      panic!("");
+    }
+    config.compact_volume()?;
+
     Ok(())
   } else if option.show {
     let config = Config::new()?;
@@ -49,10 +76,26 @@ pub fn deal_with_options(option: &options::Options) -> io::Result<()> {
     crate::logln!("Pid: ", config.tpid);
     crate::logln!("Pid File: ", config.pid_file);
     crate::logln!("Physical Volume: ", config.volume_name);
+    crate::logln!("Max Volume Size: ", config.max_volume_size);
+    crate::logln!("Max Chunked Body: ", config.max_chunked_body);
+    let volumes = config.volumes()?;
+    for id in volumes.ids() {
+      let path = volumes.path(*id);
+      let superblock = Superblock::read_from(&mut fs::File::open(&path)?)?;
+      let state = if *id == volumes.writable() { "writable" } else { "read-only" };
+      crate::logln!("  ", path, ": format ", superblock.version, ", created ", superblock.created,
+        ", ", fs::metadata(&path)?.len(), " bytes, ", state);
+    }
     crate::logln!("Index File: ", config.index_name);
+    crate::logln!("Key File: ", config.key_file);
     crate::logln!("Config Port: ", config.config_port);
     crate::logln!("Service Port: ", config.service_port);
     crate::logln!("Max Index Mem: ", config.max_index_in_mem);
+    if config.is_started() {
+      // the journal and the volumes are being written, try GET /stats on the config port
+      return Ok(());
+    }
+    show_stats(&master::inspect_index_file(&config)?.stats()?);
 
     Ok(())
   } else {
@@ -62,6 +105,26 @@ pub fn deal_with_options(option: &options::Options) -> io::Result<()> {
   // unimplemented!("");
 }
 
+/// print what ``GET /stats`` returns
+fn show_stats(stats: &Stats) {
+  crate::logln!("Live Needles: ", stats.live_needles, ", ", stats.live_bytes, " bytes");
+  crate::logln!("Deleted Needles: ", stats.deleted_needles, ", ", stats.dead_bytes, " bytes");
+  crate::logln!("Volume Size: ", stats.volume_bytes, " bytes");
+  crate::logln!("Garbage Ratio: ", format!("{:.4}", stats.garbage_ratio));
+  crate::logln!("Index Mem: ", stats.index_mem_bytes, " of ", stats.max_index_mem_bytes, " bytes");
+  for volume in &stats.volumes {
+    crate::logln!("  volume ", volume.volume, ": ", volume.size, " bytes, live ", volume.live_needles, " (", volume.live_bytes,
+      " bytes), deleted ", volume.deleted_needles, " (", volume.dead_bytes, " bytes)");
+  }
+  crate::logln!("Size Histogram:");
+  for bucket in &stats.size_histogram {
+    match bucket.le {
+      Some(le) => crate::logln!("  <= ", le, " bytes: ", bucket.files),
+      None => crate::logln!("  larger: ", bucket.files)
+    }
+  }
+}
+
 fn show_usage() {
   crate::logln!("HeyStack\n");
   crate::logln!("Arguments:");
@@ -70,4 +133,5 @@ fn show_usage() {
   crate::logln!("  show        Show the config file");
   crate::logln!("  r, reload   Reload program from config file");
   crate::logln!("  stop        Stop the program");
+  crate::logln!("  compact     Reclaim the space of deleted files in the volume");
 }
\ No newline at end of file
@@ -2,6 +2,7 @@ mod diskio;
 mod init;
 mod config;
 mod master;
+mod metrics;
 mod storage;
 
 #[macro_use] mod log;
@@ -0,0 +1,170 @@
+//! the operations of the store, served on config_port apart from the files, see ``service_start``
+
+use std::io;
+use std::sync::atomic::Ordering;
+
+use actix_web::{ web, get, post, put, delete, Responder, HttpResponse };
+use actix_web::error::BlockingError;
+use serde::{Deserialize, Serialize};
+use super::{open_index_file, AppState};
+use super::route::failed;
+use crate::metrics;
+use crate::storage::compact;
+use crate::storage::{FileStat, IndexFile};
+
+/// the files listed by one page of ``GET /files`` unless the query asks for fewer
+const LIST_LIMIT: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+  after: Option<u64>,
+  limit: Option<usize>
+}
+
+/// a page of ``GET /files``, next is the cursor of the following page, if there may be one
+#[derive(Serialize)]
+struct FileList {
+  files: Vec<FileStat>,
+  next: Option<u64>
+}
+
+#[put("/sync")]
+pub async fn sync_index_file(data: web::Data<AppState>) -> impl Responder {
+  let _timer = metrics::SYNC.start();
+  let mut index_file = data.index_file.lock().unwrap();
+  match index_file.checkpoint() {
+    Err(e) => {
+      failed(&e);
+      HttpResponse::InternalServerError()
+        .body("Something went wrong")
+    },
+    _ => {
+      HttpResponse::Ok()
+        .body("done")
+    }
+  }
+
+}
+
+#[get("/stats")]
+pub async fn get_stats(data: web::Data<AppState>) -> impl Responder {
+  // every index is read, keep it off the worker thread
+  match web::block(move || data.index_file.lock().unwrap().stats()).await {
+    Ok(stats) => HttpResponse::Ok()
+      .json(stats),
+    Err(BlockingError::Error(e)) => {
+      failed(&e);
+      HttpResponse::InternalServerError()
+        .body("Something went wrong")
+    },
+    Err(e) => {
+      failed(&io::Error::new(io::ErrorKind::Other, e.to_string()));
+      HttpResponse::InternalServerError()
+        .body("Something went wrong")
+    }
+  }
+}
+
+#[get("/files")]
+pub async fn list_files(data: web::Data<AppState>, query: web::Query<ListQuery>) -> impl Responder {
+  let limit = query.limit.unwrap_or(LIST_LIMIT).min(LIST_LIMIT);
+  let files = data.index_file.lock().unwrap().list_files(query.after, limit);
+  match files {
+    Err(e) => {
+      failed(&e);
+      HttpResponse::InternalServerError()
+        .body("Something went wrong")
+    },
+    Ok(files) => {
+      let next = files.last().filter(|_| files.len() == limit).map(|stat| stat.key);
+      HttpResponse::Ok()
+        .json(FileList { files, next })
+    }
+  }
+}
+
+#[post("/compact")]
+pub async fn compact_volume(data: web::Data<AppState>) -> impl Responder {
+  // copying the volume takes a while, keep it off the worker thread
+  match web::block(move || compact::compact(&data.index_file)).await {
+    Ok(report) => HttpResponse::Ok()
+      .json(report),
+    Err(BlockingError::Error(e)) if compact::is_already_running(&e) => HttpResponse::Conflict()
+      .body("Compaction is already running"),
+    Err(BlockingError::Error(e)) => {
+      failed(&e);
+      HttpResponse::InternalServerError()
+        .body("Something went wrong")
+    },
+    Err(e) => {
+      failed(&io::Error::new(io::ErrorKind::Other, e.to_string()));
+      HttpResponse::InternalServerError()
+        .body("Something went wrong")
+    }
+  }
+}
+
+/// build the index file again from the volumes and load it, like ``reload`` does offline
+/// the index is locked meanwhile, a running compaction or upload is not interrupted
+fn reload(data: &AppState) -> io::Result<()> {
+  let config = data.config.lock().unwrap();
+  let mut index_file = data.index_file.lock().unwrap();
+  if compact::is_running() {
+    return Err(io::Error::new(io::ErrorKind::WouldBlock, "compaction is running"));
+  }
+  // the space reserved by an upload is not indexed yet, rebuild would cut it off under it
+  if index_file.uploads_in_flight() > 0 {
+    return Err(io::Error::new(io::ErrorKind::WouldBlock, "uploads are in flight"));
+  }
+  IndexFile::rebuild(&config.index_name, &config.volumes()?, &config.journal_name, config.max_indexes())?;
+  index_file.replace_with(open_index_file(&config)?);
+  Ok(())
+}
+
+#[post("/reload")]
+pub async fn reload_index_file(data: web::Data<AppState>) -> impl Responder {
+  // every volume is read, keep it off the worker thread
+  match web::block(move || reload(&data)).await {
+    Ok(()) => HttpResponse::Ok()
+      .body("done"),
+    Err(BlockingError::Error(e)) if compact::is_already_running(&e) => HttpResponse::Conflict()
+      .body("Compaction or uploads are running, try again later"),
+    Err(BlockingError::Error(e)) => {
+      failed(&e);
+      HttpResponse::InternalServerError()
+        .body("Something went wrong")
+    },
+    Err(e) => {
+      failed(&io::Error::new(io::ErrorKind::Other, e.to_string()));
+      HttpResponse::InternalServerError()
+        .body("Something went wrong")
+    }
+  }
+}
+
+/// refuse uploads, updates and deletes until ``unset_read_only``, files are still served
+#[put("/read-only")]
+pub async fn set_read_only(data: web::Data<AppState>) -> impl Responder {
+  data.read_only.store(true, Ordering::SeqCst);
+  crate::logln!("The store is read-only");
+  HttpResponse::Ok()
+    .body("done")
+}
+
+#[delete("/read-only")]
+pub async fn unset_read_only(data: web::Data<AppState>) -> impl Responder {
+  data.read_only.store(false, Ordering::SeqCst);
+  crate::logln!("The store is writable");
+  HttpResponse::Ok()
+    .body("done")
+}
+
+/// stop the service like SIGTERM does, see ``shutdown``
+#[post("/stop")]
+pub async fn stop_service(data: web::Data<AppState>) -> impl Responder {
+  if let Some(stop) = data.stop.lock().unwrap().take() {
+    let _ = stop.send(());
+  }
+  HttpResponse::Ok()
+    .body("stopping")
+}
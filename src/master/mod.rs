@@ -1,75 +1,164 @@
 //! The main service of the program
 
 use ::std::io;
-use ::std::fs;
+use ::std::sync::atomic::AtomicBool;
 use ::std::sync::Mutex;
 
+use actix_web::dev::Server;
+use actix_web::rt::signal::unix::{signal, SignalKind};
 use actix_web::{web, App, HttpServer};
+use futures::channel::oneshot;
 
-use crate::diskio::read_write::read_struct_from_file;
-use crate::config::Config;
+use crate::config::{self, Config};
 use crate::storage::{
   IndexFile,
   IndexFileItem
 };
 
+mod admin;
+mod range;
 mod route;
 
 /// share value in different route
 #[derive(Debug)]
 pub struct AppState {
   pub index_file: Mutex<IndexFile>,
-  pub config: Mutex<Config>
+  pub config: Mutex<Config>,
+  pub read_only: AtomicBool,                   // uploads, updates and deletes are refused
+  pub stop: Mutex<Option<oneshot::Sender<()>>> // taken by ``POST /stop``
+}
+
+/// removes the pid file when the service is gone, also when it fails to start or to stop
+struct PidFile(String);
+
+impl Drop for PidFile {
+  fn drop(&mut self) {
+    if let Err(e) = config::remove_pid_file(&self.0) {
+      route::failed(&e);
+    }
+  }
 }
 
 #[actix_web::main]
 pub async fn service_start(config: Config) -> io::Result<()> {
+  let _pid_file = PidFile(config.pid_file.clone());
   // 1. load index file
   // 1. currently, load all index
-  let indexes = load_index_file(&config)?;
   let service_port = config.service_port;
-  let max_index_in_mem = config.max_index_in_mem / std::mem::size_of::<IndexFileItem>() as u64;
+  let config_port = config.config_port;
 
   // 2. use web-framework to start http listening
   // 2. share indexes
-  let index_file = Mutex::new(IndexFile::new(
-    indexes,
-    max_index_in_mem as usize,
-    config.index_name.clone(),
-    config.volume_name.clone()
-  ));
+  let index_file = Mutex::new(open_index_file(&config)?);
+  let (stop, stopped) = oneshot::channel();
   let state = web::Data::new(AppState {
     index_file,
-    config: Mutex::new(config)
+    config: Mutex::new(config),
+    read_only: AtomicBool::new(false),
+    stop: Mutex::new(Some(stop))
   });
 
   crate::logln!("Trying to bind port: ", service_port);
-  HttpServer::new(move || {
+  let service_state = state.clone();
+  let service = HttpServer::new(move || {
     App::new()
-      .app_data(state.clone())
-      .service(route::sync_index_file)
+      .app_data(service_state.clone())
+      .service(route::get_meta)
+      .service(route::head_file)
       .service(route::get_file)
+      .service(route::get_versions)
+      .service(route::get_metrics)
       .service(route::upload_file)
       .service(route::delete_file)
       .service(route::update_file)
   })
-    .bind(&format!("0.0.0.0:{}", service_port))?
-    .run()
-    .await
-}
+    // SIGINT is handled like SIGTERM, see ``shutdown``
+    .disable_signals()
+    .bind(format!("0.0.0.0:{}", service_port))?
+    .run();
 
-pub fn load_index_file(config: &Config) -> io::Result<Vec::<IndexFileItem>> {
-  let mut v: Vec::<IndexFileItem> = vec![];
-  let mut f = fs::File::open(&config.index_name)?;
+  // the operations of the store are only served to the local host
+  crate::logln!("Trying to bind admin port: ", config_port);
+  let admin_state = state.clone();
+  let admin = HttpServer::new(move || {
+    App::new()
+      .app_data(admin_state.clone())
+      .service(admin::sync_index_file)
+      .service(admin::compact_volume)
+      .service(admin::reload_index_file)
+      .service(admin::set_read_only)
+      .service(admin::unset_read_only)
+      .service(admin::get_stats)
+      .service(admin::list_files)
+      .service(admin::stop_service)
+  })
+    .disable_signals()
+    .workers(1)
+    .bind(format!("127.0.0.1:{}", config_port))?
+    .run();
+
+  actix_web::rt::spawn(shutdown(vec![service.clone(), admin.clone()], stopped));
+  futures::future::try_join(service, admin).await?;
 
-  let mut index_count = 0 as u32;
-  while let Some(item) = read_struct_from_file::<IndexFileItem>(&mut f)? {
-    if item.file_exists() == false {
-      continue
+  // no request is running anymore
+  crate::logln!("Flushing the index file and the volumes");
+  if let Err(e) = state.index_file.lock().unwrap().close() {
+    route::failed(&e);
+    return Err(e);
+  }
+  crate::logln!("Stopped");
+  Ok(())
+}
+
+/// stop servers on SIGTERM (sent by ``stop``), SIGINT (Ctrl+C) or ``POST /stop``
+/// the requests in flight are finished first, new connections are refused
+async fn shutdown(servers: Vec<Server>, stopped: oneshot::Receiver<()>) {
+  let signals = signal(SignalKind::terminate()).and_then(|term| Ok((term, signal(SignalKind::interrupt())?)));
+  match signals {
+    Ok((mut term, mut int)) => {
+      let signal = futures::future::select(Box::pin(term.recv()), Box::pin(int.recv()));
+      futures::future::select(signal, stopped).await;
+    },
+    Err(e) => {
+      crate::logln!("Cannot listen for signals: ", e);
+      let _ = stopped.await;
     }
+  }
+  crate::logln!("Stopping, waiting for the requests in flight");
+  futures::future::join_all(servers.iter().map(|server| server.stop(true))).await;
+}
+
+/// the index of the store as the service uses it, at most max_index_in_mem bytes of indexes are in memory
+pub fn open_index_file(config: &Config) -> io::Result<IndexFile> {
+  let indexes = load_index_file(config)?;
+  IndexFile::new(
+    indexes,
+    config.max_indexes(),
+    config.keep_versions,
+    config.index_name.clone(),
+    config.volumes()?,
+    config.keys()?,
+    config.journal()?
+  )
+}
+
+/// the index of the store as ``open_index_file`` finds it, for a look at it while the service is not running
+/// nothing is cut off or written
+pub fn inspect_index_file(config: &Config) -> io::Result<IndexFile> {
+  IndexFile::inspect(
+    &config.index_name,
+    config.max_indexes(),
+    config.keep_versions,
+    config.volumes()?,
+    config.keys()?,
+    &config.journal_name
+  )
+}
+
+pub fn load_index_file(config: &Config) -> io::Result<Vec::<IndexFileItem>> {
+  let v = IndexFile::recover(&config.index_name, &config.volumes()?, &config.journal_name)?;
+  for (index_count, item) in v.iter().enumerate() {
     println!("{} // loading index: {:?}", index_count, item);
-    v.push(item);
-    index_count += 1;
   }
 
   Ok(v)
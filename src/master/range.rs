@@ -0,0 +1,64 @@
+//! the parts of a file asked for by the Range header of a request
+
+/// the bytes asked for by a Range header of a file with size bytes, as (start, length)
+/// only a single range of bytes is served, None if the header asks for something else,
+/// then the whole file is sent. Err(()) if the range is out of the file, every range is for an empty one
+pub fn parse(value: &str, size: u64) -> Option<Result<(u64, u64), ()>> {
+  let spec = value.trim().strip_prefix("bytes=")?;
+  if spec.contains(',') {
+    return None;
+  }
+  let (first, last) = spec.split_once('-')?;
+  let (first, last) = (first.trim(), last.trim());
+  let range = if first.is_empty() {
+    // the last bytes of the file
+    let suffix = last.parse::<u64>().ok()?;
+    let len = suffix.min(size);
+    if len == 0 {
+      return Some(Err(()));
+    }
+    (size - len, len)
+  } else {
+    let first = first.parse::<u64>().ok()?;
+    let last = match last {
+      "" => u64::MAX,
+      last => last.parse::<u64>().ok()?
+    };
+    // not a valid range, the header is ignored (RFC 7233 2.1)
+    if last < first {
+      return None;
+    }
+    if first >= size {
+      return Some(Err(()));
+    }
+    (first, last.min(size - 1) - first + 1)
+  };
+  Some(Ok(range))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn ranges_of_bytes() {
+    assert_eq!(parse("bytes=0-9", 100), Some(Ok((0, 10))));
+    assert_eq!(parse("bytes=90-", 100), Some(Ok((90, 10))));
+    assert_eq!(parse("bytes=90-200", 100), Some(Ok((90, 10))));
+    assert_eq!(parse("bytes=-10", 100), Some(Ok((90, 10))));
+    assert_eq!(parse("bytes=-200", 100), Some(Ok((0, 100))));
+
+    assert_eq!(parse("bytes=100-", 100), Some(Err(())));
+    assert_eq!(parse("bytes=-0", 100), Some(Err(())));
+    assert_eq!(parse("bytes=0-", 0), Some(Err(())));
+    assert_eq!(parse("bytes=-5", 0), Some(Err(())));
+    assert_eq!(parse("bytes=a-b", 0), None);
+
+    // served as a whole
+    assert_eq!(parse("bytes=0-1,5-6", 100), None);
+    assert_eq!(parse("items=0-1", 100), None);
+    assert_eq!(parse("bytes=a-b", 100), None);
+    assert_eq!(parse("bytes=5-4", 100), None);
+    assert_eq!(parse("bytes=150-120", 100), None);
+  }
+}
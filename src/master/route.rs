@@ -1,96 +1,418 @@
-use actix_web::{ web, get, post, put, delete, Responder, HttpResponse, Error };
-use super::AppState;
-use futures::StreamExt;
-
-#[put("/sync")]
-pub async fn sync_index_file(data: web::Data<AppState>) -> impl Responder {
-  let index_file = data.index_file.lock().unwrap();
-  match index_file.store_into_file() {
+use actix_web::{ web, get, head, post, put, delete, Responder, HttpRequest, HttpResponse, Error };
+use actix_web::error::BlockingError;
+use actix_web::http::StatusCode;
+use actix_web::http::header::{self, Header, ContentDisposition, DispositionParam, DispositionType};
+use actix_web::http::header::{ContentRange, ContentRangeSpec, ETag, EntityTag, HttpDate, IfModifiedSince, IfNoneMatch, IfRange, LastModified};
+use super::{range, AppState};
+use crate::metrics;
+use crate::storage;
+use crate::storage::compact;
+use crate::storage::meta::{self, Metadata};
+use crate::storage::NeedleData;
+use futures::{Stream, StreamExt};
+use std::io;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct VersionQuery {
+  version: Option<u32>
+}
+
+/// the metadata of a file sent in the request: its Content-Type,
+/// the filename of its Content-Disposition and the X-Heystack-Meta-* headers
+fn metadata_of(req: &HttpRequest) -> Metadata {
+  let headers = req.headers();
+  Metadata {
+    content_type: headers.get(header::CONTENT_TYPE)
+      .and_then(|value| value.to_str().ok())
+      .map(str::to_string),
+    filename: headers.get(header::CONTENT_DISPOSITION)
+      .and_then(|value| ContentDisposition::from_raw(value).ok())
+      .and_then(|disposition| disposition.get_filename().map(str::to_string)),
+    headers: headers.iter()
+      .filter(|(name, _)| name.as_str().starts_with(meta::HEADER_PREFIX))
+      .filter_map(|(name, value)| value.to_str().ok().map(|value| (name.as_str().to_string(), value.to_string())))
+      .collect()
+  }
+}
+
+/// log an error that fails a request and count it, see ``metrics::count_error``
+pub fn failed(e: &io::Error) {
+  crate::logln!(e);
+  metrics::count_error(e);
+}
+
+/// the chunks of the data of a needle, each one read on the thread pool
+/// a chunk that cannot be read ends the response early
+fn stream_of(data: NeedleData) -> impl Stream<Item = Result<web::Bytes, Error>> + Unpin {
+  Box::pin(futures::stream::unfold(Some(data), |data| async move {
+    let mut data = data?;
+    match web::block(move || Ok::<_, io::Error>((data.next(), data))).await {
+      Ok((Some(Ok(chunk)), data)) => {
+        metrics::BYTES_READ.add(chunk.len() as u64);
+        Some((Ok(web::Bytes::from(chunk)), Some(data)))
+      },
+      Ok((None, _)) => None,
+      Ok((Some(Err(e)), _)) => {
+        failed(&e);
+        Some((Err(e.into()), None))
+      },
+      Err(e) => Some((Err(e.into()), None))
+    }
+  }))
+}
+
+/// the ETag of a version of a file, a new generation gets a new one
+fn etag_of(key: u64, generation: u32) -> EntityTag {
+  EntityTag::strong(format!("{}.{}", key, generation))
+}
+
+/// check if the client already has the version of a file, by If-None-Match or else by If-Modified-Since
+fn not_modified(req: &HttpRequest, etag: &EntityTag, modified: SystemTime) -> bool {
+  if req.headers().contains_key(header::IF_NONE_MATCH) {
+    return match IfNoneMatch::parse(req) {
+      Ok(IfNoneMatch::Any) => true,
+      Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+      Err(_) => false
+    };
+  }
+  match IfModifiedSince::parse(req) {
+    Ok(IfModifiedSince(since)) => modified <= SystemTime::from(since),
+    Err(_) => false
+  }
+}
+
+/// check if the Range of req may be served, a stale If-Range asks for the whole file
+fn range_applies(req: &HttpRequest, etag: &EntityTag, modified: SystemTime) -> bool {
+  if !req.headers().contains_key(header::IF_RANGE) {
+    return true;
+  }
+  match IfRange::parse(req) {
+    Ok(IfRange::EntityTag(tag)) => tag.strong_eq(etag),
+    Ok(IfRange::Date(date)) => modified <= SystemTime::from(date),
+    Err(_) => false
+  }
+}
+
+#[head("/file/{key}/{cookie}")]
+pub async fn head_file(data: web::Data<AppState>, web::Path((key, cookie)): web::Path<(u64, u32)>) -> impl Responder {
+  let stat = data.index_file.lock().unwrap().stat_file(key, cookie);
+  match stat {
     Err(e) => {
-      println!("{:?}", e);
+      failed(&e);
+      HttpResponse::InternalServerError()
+        .finish()
+    },
+    Ok(None) => HttpResponse::NotFound()
+      .finish(),
+    Ok(Some(stat)) => {
+      let mut r = HttpResponse::Ok();
+      r.set(ETag(etag_of(stat.key, stat.generation)))
+        .set(LastModified(HttpDate::from(UNIX_EPOCH + Duration::from_secs(stat.created))))
+        .header(header::ACCEPT_RANGES, "bytes");
+      if let Some(content_type) = stat.meta.content_type {
+        r.content_type(content_type);
+      }
+      // an empty stream keeps the Content-Length of the file, the body of a HEAD is never sent
+      r.no_chunking(stat.size)
+        .streaming(futures::stream::empty::<Result<web::Bytes, Error>>())
+    }
+  }
+}
+
+#[get("/file/{key}/{cookie}/meta")]
+pub async fn get_meta(data: web::Data<AppState>, web::Path((key, cookie)): web::Path<(u64, u32)>) -> impl Responder {
+  let stat = data.index_file.lock().unwrap().stat_file(key, cookie);
+  match stat {
+    Err(e) => {
+      failed(&e);
       HttpResponse::InternalServerError()
         .body("Something went wrong")
     },
-    _ => {
-      HttpResponse::Ok()
-        .body("done")
+    Ok(None) => HttpResponse::NotFound()
+      .body("Resource Not Found"),
+    Ok(Some(stat)) => HttpResponse::Ok()
+      .json(stat)
+  }
+}
+
+#[get("/file/{key}/{cookie}")]
+pub async fn get_file(
+  req: HttpRequest,
+  data: web::Data<AppState>,
+  web::Path((key, cookie)): web::Path<(u64, u32)>,
+  query: web::Query<VersionQuery>
+) -> impl Responder {
+  let _timer = metrics::GET.start();
+  // the index is only needed to find the needle, the data is read without it
+  let file = data.index_file.lock().unwrap().open_file(key, cookie, query.version);
+  let mut file = match file {
+    Err(e) => {
+      failed(&e);
+      return HttpResponse::InternalServerError()
+        .body("Something went wrong");
+    },
+    Ok(None) => {
+      return HttpResponse::NotFound()
+        .body("Resource Not Found");
+    },
+    Ok(Some(file)) => file
+  };
+
+  let etag = etag_of(file.key, file.generation);
+  let modified = UNIX_EPOCH + Duration::from_secs(file.created);
+  let mut r = HttpResponse::Ok();
+  r.set(ETag(etag.clone()))
+    .set(LastModified(HttpDate::from(modified)))
+    .header(header::ACCEPT_RANGES, "bytes");
+  if not_modified(&req, &etag, modified) {
+    return r.status(StatusCode::NOT_MODIFIED).finish();
+  }
+
+  let range = req.headers().get(header::RANGE)
+    .and_then(|value| value.to_str().ok())
+    .filter(|_| range_applies(&req, &etag, modified))
+    .and_then(|value| range::parse(value, file.size));
+  match range {
+    Some(Err(())) => {
+      return r.status(StatusCode::RANGE_NOT_SATISFIABLE)
+        .set(ContentRange(ContentRangeSpec::Bytes { range: None, instance_length: Some(file.size) }))
+        .finish();
+    },
+    Some(Ok((start, len))) => {
+      if let Err(e) = file.data.seek(start, len) {
+        failed(&e);
+        return HttpResponse::InternalServerError()
+          .body("Something went wrong");
+      }
+      r.status(StatusCode::PARTIAL_CONTENT)
+        .set(ContentRange(ContentRangeSpec::Bytes { range: Some((start, start + len - 1)), instance_length: Some(file.size) }));
+    },
+    None => {
+      // a small file is checked before the body starts, so a corrupted one is answered with 500, not cut off
+      // the data read by the check is sent, a larger file is checked while it is sent
+      let checked = web::block(move || file.data.check().map(|_| file)).await;
+      file = match checked {
+        Ok(file) => file,
+        Err(e) => {
+          let e = match e {
+            BlockingError::Error(e) => e,
+            e => io::Error::new(io::ErrorKind::Other, e.to_string())
+          };
+          failed(&e);
+          return HttpResponse::InternalServerError()
+            .body(if storage::is_corrupted(&e) { "File is corrupted" } else { "Something went wrong" });
+        }
+      };
     }
   }
 
+  let meta = file.meta;
+  r.no_chunking(file.data.left());
+  if let Some(content_type) = meta.content_type {
+    r.content_type(content_type);
+  }
+  if let Some(filename) = meta.filename {
+    r.set(ContentDisposition {
+      disposition: DispositionType::Attachment,
+      parameters: vec![DispositionParam::Filename(filename)]
+    });
+  }
+  for (name, value) in meta.headers {
+    r.header(name.as_str(), value);
+  }
+  r.streaming(stream_of(file.data))
+}
+
+#[get("/metrics")]
+pub async fn get_metrics(data: web::Data<AppState>) -> impl Responder {
+  let gauges = data.index_file.lock().unwrap().gauges();
+  match gauges {
+    Err(e) => {
+      failed(&e);
+      HttpResponse::InternalServerError()
+        .body("Something went wrong")
+    },
+    Ok(gauges) => HttpResponse::Ok()
+      .content_type("text/plain; version=0.0.4")
+      .body(metrics::render(&gauges))
+  }
 }
 
-#[get("/file/{key}")]
-pub async fn get_file(data: web::Data<AppState>, web::Path(key): web::Path<u32>) -> impl Responder {
+#[get("/file/{key}/{cookie}/versions")]
+pub async fn get_versions(data: web::Data<AppState>, web::Path((key, cookie)): web::Path<(u64, u32)>) -> impl Responder {
   let mut index_file = data.index_file.lock().unwrap();
-  match index_file.get_data(key) {
-    Err(_) => {
+  match index_file.get_versions(key, cookie) {
+    Err(e) => {
+      failed(&e);
       HttpResponse::InternalServerError()
         .body("Something went wrong")
     },
-    Ok(r) => match r {
-      None => {
-        HttpResponse::NotFound()
-          .body("Resource Not Found")
-      },
-      Some(t) => {
-        HttpResponse::Ok()
-          .body(t)
+    Ok(None) => HttpResponse::NotFound()
+      .body("Resource Not Found"),
+    Ok(Some(versions)) => HttpResponse::Ok()
+      .json(versions)
+  }
+}
+
+/// the answer to a change while the store is read-only, see ``admin::set_read_only``
+fn read_only() -> HttpResponse {
+  HttpResponse::ServiceUnavailable()
+    .body("The store is read-only")
+}
+
+/// the answer to a file that does not fit into a volume, see ``Config::max_volume_size``
+fn too_large() -> HttpResponse {
+  HttpResponse::PayloadTooLarge()
+    .body("File is larger than a volume")
+}
+
+/// the answer to a body without Content-Length that is too large to be collected in memory, see ``Config::max_chunked_body``
+fn chunked_too_large() -> HttpResponse {
+  HttpResponse::PayloadTooLarge()
+    .body("Body without Content-Length is too large, send Content-Length")
+}
+
+/// write the body of req into a new file, or a new version of key if it is given with its cookie
+/// every chunk is written into the volume as it arrives, on the thread pool and without the index
+/// a body without Content-Length is collected in memory first, its size is known only at its end,
+/// up to ``Config::max_chunked_body`` bytes
+/// a body that ends early leaves no file behind
+async fn receive(req: HttpRequest, mut body: web::Payload, data: web::Data<AppState>, key: Option<(u64, u32)>) -> Result<HttpResponse, Error> {
+  if data.read_only.load(Ordering::SeqCst) {
+    return Ok(read_only());
+  }
+  let metadata = metadata_of(&req);
+  let (max_volume_size, max_chunked_body) = {
+    let config = data.config.lock().unwrap();
+    (config.max_volume_size, config.max_chunked_body)
+  };
+  let (size, mut body) = match req.headers().get(header::CONTENT_LENGTH)
+    .and_then(|value| value.to_str().ok())
+    .and_then(|value| value.parse::<u64>().ok()) {
+    Some(size) => (size, body.boxed_local()),
+    None => {
+      let mut bytes = web::BytesMut::new();
+      while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        if bytes.len() as u64 + chunk.len() as u64 > max_chunked_body {
+          return Ok(chunked_too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+        if !storage::fits(&metadata, bytes.len() as u64, max_volume_size) {
+          return Ok(too_large());
+        }
       }
+      (bytes.len() as u64, futures::stream::once(futures::future::ok(bytes.freeze())).boxed_local())
     }
+  };
+  // before the index is locked, a size that cannot be stored must not reach it
+  if !storage::fits(&metadata, size, max_volume_size) {
+    return Ok(too_large());
   }
-}
 
-#[post("/file")]
-pub async fn upload_file(mut body: web::Payload, data: web::Data<AppState>) -> Result<HttpResponse, Error> {
-  let mut bytes = web::BytesMut::new();
-  while let Some(item) = body.next().await {
-    let item = item?;
-    bytes.extend_from_slice(&item);
+  let upload = {
+    let mut index_file = data.index_file.lock().unwrap();
+    match key {
+      Some((key, cookie)) => match index_file.has_file(key, cookie) {
+        Ok(true) => index_file.start_upload(Some(key), &metadata, size),
+        r => r.map(|_| None)
+      },
+      None => index_file.start_upload(None, &metadata, size)
+    }
+  };
+  let mut upload = match upload {
+    Err(e) if e.kind() == io::ErrorKind::InvalidInput => return Ok(too_large()),
+    Err(e) => {
+      failed(&e);
+      return Ok(HttpResponse::InternalServerError()
+        .body("Something went wrong"));
+    },
+    Ok(None) => return Ok(HttpResponse::NotFound()
+      .body("Resource Not Found")),
+    Ok(Some(upload)) => upload
+  };
+  while let Some(chunk) = body.next().await {
+    let chunk = chunk?;
+    let len = chunk.len() as u64;
+    upload = match web::block(move || upload.write(&chunk).map(|_| upload)).await {
+      Ok(upload) => upload,
+      Err(BlockingError::Error(e)) if e.kind() == io::ErrorKind::InvalidInput => return Ok(HttpResponse::BadRequest()
+        .body("Body is longer than Content-Length")),
+      Err(BlockingError::Error(e)) => {
+        failed(&e);
+        return Ok(HttpResponse::InternalServerError()
+          .body("Something went wrong"));
+      },
+      Err(e) => {
+        failed(&io::Error::new(io::ErrorKind::Other, e.to_string()));
+        return Ok(HttpResponse::InternalServerError()
+          .body("Something went wrong"));
+      }
+    };
+    metrics::BYTES_WRITTEN.add(len);
   }
 
-  let d = &bytes[..];
-  let mut index_file = data.index_file.lock().unwrap();
-  match index_file.add_item(&d.to_vec()) {
-    Err(_) => {
+  let r = data.index_file.lock().unwrap().finish_upload(upload);
+  match r {
+    Err(e) if e.kind() == io::ErrorKind::InvalidInput => Ok(HttpResponse::BadRequest()
+      .body("Body is shorter than Content-Length")),
+    Err(e) if compact::is_already_running(&e) => {
+      metrics::count_error(&e);
+      Ok(HttpResponse::Conflict()
+        .body("The volume was compacted during the upload, try again"))
+    },
+    Err(e) => {
+      failed(&e);
       Ok(HttpResponse::InternalServerError()
         .body("Something went wrong"))
     },
-    Ok(ifi) => {
-      Ok(HttpResponse::Ok()
-        .json(ifi))
-    }
+    Ok(None) => Ok(HttpResponse::NotFound()
+      .body("Resource Not Found")),
+    Ok(Some(ifi)) => Ok(HttpResponse::Ok()
+      .json(ifi))
   }
 }
 
-#[delete("/file/{key}")]
-pub async fn delete_file(data: web::Data<AppState>, web::Path(key): web::Path<u32>) -> impl Responder {
+#[post("/file")]
+pub async fn upload_file(req: HttpRequest, body: web::Payload, data: web::Data<AppState>) -> Result<HttpResponse, Error> {
+  let _timer = metrics::UPLOAD.start();
+  receive(req, body, data, None).await
+}
+
+#[delete("/file/{key}/{cookie}")]
+pub async fn delete_file(data: web::Data<AppState>, web::Path((key, cookie)): web::Path<(u64, u32)>) -> impl Responder {
+  let _timer = metrics::DELETE.start();
+  if data.read_only.load(Ordering::SeqCst) {
+    return read_only();
+  }
   let mut index_file = data.index_file.lock().unwrap();
-  match index_file.delete_item(key) {
-    Err(_) => HttpResponse::InternalServerError()
-      .body("Something went wrong"),
-    _ => HttpResponse::Ok()
+  let deleted = match index_file.has_file(key, cookie) {
+    Ok(true) => index_file.delete_item(key).map(|_| true),
+    r => r
+  };
+  match deleted {
+    Err(e) => {
+      failed(&e);
+      HttpResponse::InternalServerError()
+        .body("Something went wrong")
+    },
+    Ok(false) => HttpResponse::NotFound()
+      .body("Resource Not Found"),
+    Ok(true) => HttpResponse::Ok()
       .body("File has deleted")
   }
 }
 
-#[put("/file/{key}")]
-pub async fn update_file(data: web::Data<AppState>, web::Path(key): web::Path<u32>, mut body: web::Payload) -> Result<HttpResponse, Error> {
-  let mut index_file = data.index_file.lock().unwrap();
-  let mut bytes = web::BytesMut::new();
-  while let Some(item) = body.next().await {
-    let item = item?;
-    bytes.extend_from_slice(&item);
-  }
-  let d = &bytes[..];
-
-  // update = delete + add
-  match index_file.delete_item(key) {
-    Err(_) => Ok(HttpResponse::InternalServerError()
-        .body("Cannot delete original file")),
-    _ => match index_file.add_item(&d.to_vec()) {
-      Err(_) => Ok(HttpResponse::InternalServerError()
-        .body("Something went wrong")),
-      Ok(ifi) => Ok(HttpResponse::Ok().json(ifi))
-    }
-  }
+#[put("/file/{key}/{cookie}")]
+pub async fn update_file(
+  req: HttpRequest,
+  data: web::Data<AppState>,
+  web::Path((key, cookie)): web::Path<(u64, u32)>,
+  body: web::Payload
+) -> Result<HttpResponse, Error> {
+  let _timer = metrics::UPDATE.start();
+  receive(req, body, data, Some((key, cookie))).await
 }
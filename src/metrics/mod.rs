@@ -0,0 +1,222 @@
+//! counters of the service, kept in atomics and rendered in the Prometheus text format by ``GET /metrics``
+
+use std::fmt::Write;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::storage;
+
+/// the upper bounds of the buckets of every latency histogram, in seconds
+const LATENCY_BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// the kinds errors are counted by, see ``kind_of``
+const ERROR_KINDS: [&str; 7] = ["corrupted", "not_found", "permission_denied", "invalid_input", "would_block", "unexpected_eof", "other"];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+  const fn new() -> Self {
+    Counter(ZERO)
+  }
+
+  pub fn add(&self, n: u64) {
+    self.0.fetch_add(n, Ordering::Relaxed);
+  }
+
+  pub fn get(&self) -> u64 {
+    self.0.load(Ordering::Relaxed)
+  }
+}
+
+/// the number of observations in every bucket of LATENCY_BUCKETS, the last one for the larger ones
+pub struct Histogram {
+  buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+  count: AtomicU64,
+  sum: AtomicU64 // nanoseconds
+}
+
+impl Histogram {
+  const fn new() -> Self {
+    Histogram {
+      buckets: [ZERO; LATENCY_BUCKETS.len() + 1],
+      count: ZERO,
+      sum: ZERO
+    }
+  }
+
+  pub fn observe(&self, d: Duration) {
+    let seconds = d.as_secs_f64();
+    self.buckets[LATENCY_BUCKETS.partition_point(|le| *le < seconds)].fetch_add(1, Ordering::Relaxed);
+    self.count.fetch_add(1, Ordering::Relaxed);
+    self.sum.fetch_add(d.as_nanos() as u64, Ordering::Relaxed);
+  }
+
+  /// run f and observe how long it takes
+  pub fn time<T>(&self, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let r = f();
+    self.observe(start.elapsed());
+    r
+  }
+
+  fn render(&self, out: &mut String, name: &str, labels: &str) {
+    let sep = if labels.is_empty() { "" } else { "," };
+    let mut cumulative = 0;
+    for (i, bucket) in self.buckets.iter().enumerate() {
+      cumulative += bucket.load(Ordering::Relaxed);
+      let le = LATENCY_BUCKETS.get(i).map_or("+Inf".to_string(), |le| le.to_string());
+      let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, sep, le, cumulative);
+    }
+    let labels = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) };
+    let _ = writeln!(out, "{}_sum{} {}", name, labels, self.sum.load(Ordering::Relaxed) as f64 / 1e9);
+    let _ = writeln!(out, "{}_count{} {}", name, labels, self.count.load(Ordering::Relaxed));
+  }
+}
+
+/// the requests of one route and how long they take
+pub struct Route {
+  name: &'static str,
+  requests: Counter,
+  latency: Histogram
+}
+
+impl Route {
+  const fn new(name: &'static str) -> Self {
+    Route {
+      name,
+      requests: Counter::new(),
+      latency: Histogram::new()
+    }
+  }
+
+  /// count a request, its latency is observed when the timer is dropped
+  pub fn start(&'static self) -> Timer {
+    self.requests.add(1);
+    Timer {
+      histogram: &self.latency,
+      start: Instant::now()
+    }
+  }
+}
+
+pub struct Timer {
+  histogram: &'static Histogram,
+  start: Instant
+}
+
+impl Drop for Timer {
+  fn drop(&mut self) {
+    self.histogram.observe(self.start.elapsed());
+  }
+}
+
+pub static GET: Route = Route::new("get");
+pub static UPLOAD: Route = Route::new("upload");
+pub static DELETE: Route = Route::new("delete");
+pub static UPDATE: Route = Route::new("update");
+pub static SYNC: Route = Route::new("sync");
+const ROUTES: [&Route; 5] = [&GET, &UPLOAD, &DELETE, &UPDATE, &SYNC];
+
+/// bytes of file data sent to and received from clients
+pub static BYTES_READ: Counter = Counter::new();
+pub static BYTES_WRITTEN: Counter = Counter::new();
+
+/// how long it takes to make the volumes, the journal and the index file durable
+pub static FSYNC: Histogram = Histogram::new();
+
+static ERRORS: [Counter; ERROR_KINDS.len()] = [
+  Counter::new(), Counter::new(), Counter::new(), Counter::new(), Counter::new(), Counter::new(), Counter::new()
+];
+
+fn kind_of(e: &io::Error) -> usize {
+  if storage::is_corrupted(e) {
+    return 0;
+  }
+  match e.kind() {
+    io::ErrorKind::NotFound => 1,
+    io::ErrorKind::PermissionDenied => 2,
+    io::ErrorKind::InvalidInput => 3,
+    io::ErrorKind::WouldBlock => 4,
+    io::ErrorKind::UnexpectedEof => 5,
+    _ => 6
+  }
+}
+
+/// count an error that failed a request
+pub fn count_error(e: &io::Error) {
+  ERRORS[kind_of(e)].add(1);
+}
+
+/// the state of the store at the time of a scrape
+pub struct Gauges {
+  pub indexes: u64,         // in the index file and the memtable
+  pub index_mem_bytes: u64,
+  pub volume_bytes: u64
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+  let _ = writeln!(out, "# HELP {} {}", name, help);
+  let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// every metric in the Prometheus text format
+pub fn render(gauges: &Gauges) -> String {
+  let mut out = String::new();
+
+  header(&mut out, "heystack_requests_total", "counter", "Requests served, by route.");
+  for route in ROUTES.iter() {
+    let _ = writeln!(out, "heystack_requests_total{{route=\"{}\"}} {}", route.name, route.requests.get());
+  }
+  header(&mut out, "heystack_request_duration_seconds", "histogram", "Time to answer a request, by route.");
+  for route in ROUTES.iter() {
+    route.latency.render(&mut out, "heystack_request_duration_seconds", &format!("route=\"{}\"", route.name));
+  }
+
+  header(&mut out, "heystack_read_bytes_total", "counter", "Bytes of file data sent to clients.");
+  let _ = writeln!(out, "heystack_read_bytes_total {}", BYTES_READ.get());
+  header(&mut out, "heystack_written_bytes_total", "counter", "Bytes of file data received from clients.");
+  let _ = writeln!(out, "heystack_written_bytes_total {}", BYTES_WRITTEN.get());
+
+  header(&mut out, "heystack_fsync_duration_seconds", "histogram", "Time to flush a volume, the journal or the index file to disk.");
+  FSYNC.render(&mut out, "heystack_fsync_duration_seconds", "");
+
+  header(&mut out, "heystack_errors_total", "counter", "Errors that failed a request, by kind.");
+  for (kind, count) in ERROR_KINDS.iter().zip(ERRORS.iter()) {
+    let _ = writeln!(out, "heystack_errors_total{{kind=\"{}\"}} {}", kind, count.get());
+  }
+
+  header(&mut out, "heystack_indexes", "gauge", "Indexes in the index file and in memory, deleted ones included.");
+  let _ = writeln!(out, "heystack_indexes {}", gauges.indexes);
+  header(&mut out, "heystack_index_memory_bytes", "gauge", "Memory taken by the indexes in memory.");
+  let _ = writeln!(out, "heystack_index_memory_bytes {}", gauges.index_mem_bytes);
+  header(&mut out, "heystack_volume_bytes", "gauge", "Size of all volume files.");
+  let _ = writeln!(out, "heystack_volume_bytes {}", gauges.volume_bytes);
+
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn histogram_is_cumulative() {
+    let h = Histogram::new();
+    h.observe(Duration::from_micros(50));
+    h.observe(Duration::from_millis(2));
+    h.observe(Duration::from_secs(10));
+    let mut out = String::new();
+    h.render(&mut out, "t", "route=\"get\"");
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines[0], "t_bucket{route=\"get\",le=\"0.0001\"} 1");
+    assert_eq!(lines[3], "t_bucket{route=\"get\",le=\"0.005\"} 2");
+    assert_eq!(lines[9], "t_bucket{route=\"get\",le=\"5\"} 2");
+    assert_eq!(lines[10], "t_bucket{route=\"get\",le=\"+Inf\"} 3");
+    assert_eq!(lines[11], "t_sum{route=\"get\"} 10.00205");
+    assert_eq!(lines[12], "t_count{route=\"get\"} 3");
+  }
+}
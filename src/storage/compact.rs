@@ -0,0 +1,485 @@
+//! reclaim the space of deleted needles, one volume at a time
+//!
+//! live needles are copied into a fresh volume without holding the index lock,
+//! so the service keeps serving while the copy runs. The lock is only taken
+//! again to copy the needles appended meanwhile, swap the volumes and rewrite the offsets.
+//!
+//! the swap survives a crash: the index file with the new offsets is written to
+//! "{index}.compact" before "{volume}.compact" is renamed over the volume. Until that rename
+//! the old volume and the old index file are kept, after it the new index file is taken, see ``recover``
+
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+use crate::diskio::codec::{Codec, Reader};
+use crate::diskio::read_write;
+use crate::metrics;
+use super::volume::{Superblock, VolumeSet};
+use super::journal::Journal;
+#[cfg(test)]
+use super::keys::KeyAllocator;
+#[cfg(test)]
+use super::meta::Metadata;
+use super::runs::{self, Runs};
+use super::sorted::SortedIndex;
+use super::{IndexFile, IndexFileItem, PhysicalFileItem};
+
+/// only one compaction may run at a time
+static COMPACTING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Serialize)]
+pub struct CompactReport {
+  pub volume: u32,
+  pub live_needles: usize,
+  pub size_before: u64,
+  pub size_after: u64
+}
+
+/// check if e is raised because another compaction is running
+pub fn is_already_running(e: &io::Error) -> bool {
+  e.kind() == io::ErrorKind::WouldBlock
+}
+
+/// check if a compaction is copying volumes now
+pub fn is_running() -> bool {
+  COMPACTING.load(Ordering::SeqCst)
+}
+
+/// clears COMPACTING when dropped, also when the compaction panics
+struct Running;
+
+impl Drop for Running {
+  fn drop(&mut self) {
+    COMPACTING.store(false, Ordering::SeqCst);
+  }
+}
+
+/// the volume compacted from the volume at path, until it takes its place
+fn compacted(path: &str) -> String {
+  format!("{}.compact", path)
+}
+
+/// finish or undo a compaction cut off by a crash, before the index file at path is opened
+/// while a compacted volume is still there the swap did not happen, it is thrown away
+/// with the new index file. Otherwise the new index file replaces the old one,
+/// the journal is emptied first: it holds the offsets of the old volume
+pub fn recover(path: &str, volumes: &VolumeSet, journal_path: &str) -> io::Result<()> {
+  let mut swapped = true;
+  for id in volumes.ids() {
+    match fs::remove_file(compacted(&volumes.path(*id))) {
+      Ok(()) => {
+        crate::logln!("Undo the compaction of ", volumes.path(*id));
+        swapped = false;
+      },
+      Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+      Err(e) => return Err(e)
+    }
+  }
+  let index = compacted(path);
+  if !Path::new(&index).exists() {
+    return Ok(());
+  }
+  if swapped {
+    crate::logln!("Finish the compaction into ", path);
+    Journal::open(journal_path, usize::MAX)?.clear()?;
+    fs::rename(&index, path)
+  } else {
+    fs::remove_file(&index)
+  }
+}
+
+/// check if the volumes are swapped by a compaction that did not replace the index file at path yet
+pub fn is_unfinished(path: &str, volumes: &VolumeSet) -> bool {
+  Path::new(&compacted(path)).exists()
+    && volumes.ids().iter().all(|id| !Path::new(&compacted(&volumes.path(*id))).exists())
+}
+
+/// compact every volume behind index_file
+pub fn compact(index_file: &Mutex<IndexFile>) -> io::Result<Vec<CompactReport>> {
+  if COMPACTING.swap(true, Ordering::SeqCst) {
+    return Err(io::Error::new(io::ErrorKind::WouldBlock, "compaction is already running"));
+  }
+  let _running = Running;
+  let ids = index_file.lock().unwrap().volumes.ids().to_vec();
+  ids.into_iter()
+    .map(|volume| Compaction::start(index_file, volume).and_then(|c| c.finish(index_file)))
+    .collect()
+}
+
+/// where a copied needle went
+///
+/// layout: from u64 | to u64
+struct Move {
+  from: u64,
+  to: u64
+}
+
+impl Codec for Move {
+  const SIZE: usize = 8 + 8;
+
+  fn encode(&self, buf: &mut Vec<u8>) {
+    self.from.encode(buf);
+    self.to.encode(buf);
+  }
+
+  fn decode(buf: &[u8]) -> io::Result<Self> {
+    let mut reader = Reader::new(buf);
+    Ok(Move {
+      from: reader.read()?,
+      to: reader.read()?
+    })
+  }
+}
+
+/// the indexes are sorted on disk, at most max / 2 of them are in memory at once, see ``runs``
+struct Compaction {
+  volume: u32,
+  physical_filename: String,
+  tmp_filename: String,
+  src: fs::File,
+  dst: fs::File,
+  watermark: u64,              // the volume size when the compaction started
+  moved: String,               // the Moves of the copied needles, ordered by their old offset
+  max: usize
+}
+
+impl Compaction {
+  /// copy the needles of volume that are live now into a fresh volume
+  fn start(index_file: &Mutex<IndexFile>, volume: u32) -> io::Result<Self> {
+    let (physical_filename, watermark, live, max) = {
+      let mut index_file = index_file.lock().unwrap();
+      let physical_filename = index_file.volumes.path(volume);
+      // the uploads reserved below the watermark are not copied, they must not finish
+      index_file.next_epoch(volume);
+      let watermark = fs::metadata(&physical_filename)?.len();
+      // ordered by offset, the volume is read from its start to its end
+      let mut live = Runs::new(&format!("{}.live", compacted(&physical_filename)), index_file.max / 2, |index| index.offset);
+      index_file.live_in_volume(volume, |index| live.push(index))?;
+      (physical_filename, watermark, live, index_file.max)
+    };
+    crate::logln!("Compacting ", physical_filename, ", live needles: ", live.pushed());
+
+    let tmp_filename = compacted(&physical_filename);
+    let mut src = fs::File::open(&physical_filename)?;
+    let superblock = Superblock::read_from(&mut src)?;
+    let mut dst = fs::OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(true)
+      .open(&tmp_filename)?;
+    read_write::append_struct_to_file(&superblock, &mut dst)?;
+
+    let moved = format!("{}.moved", tmp_filename);
+    let mut moves = io::BufWriter::new(fs::File::create(&moved)?);
+    for index in live.merge()? {
+      let index = index?;
+      let bytes = PhysicalFileItem::read_raw(&index, &mut src)?;
+      moves.write_all(&Move { from: index.offset, to: dst.stream_position()? }.to_bytes())?;
+      read_write::write_bytes_to_file(&bytes, &mut dst)?;
+    }
+    moves.flush()?;
+
+    Ok(Compaction {
+      volume,
+      physical_filename,
+      tmp_filename,
+      src,
+      dst,
+      watermark,
+      moved,
+      max
+    })
+  }
+
+  /// the indexes of the needles copied by ``start`` with their new offsets, ordered by key and generation
+  /// the indexes of the needles that are not copied are left out
+  fn copied(&self, index_file: &IndexFile) -> io::Result<Runs<(u64, u32)>> {
+    let mut below = Runs::new(&format!("{}.below", self.tmp_filename), self.max / 2, |index| index.offset);
+    for index in index_file.merged()? {
+      let index = index?;
+      if index.volume == self.volume && index.offset < self.watermark {
+        below.push(index)?;
+      }
+    }
+
+    let mut copied = Runs::new(&format!("{}.copied", self.tmp_filename), self.max / 2, IndexFileItem::id);
+    let mut moves = io::BufReader::new(fs::File::open(&self.moved)?);
+    let mut next = runs::read_record::<Move>(&mut moves)?;
+    for index in below.merge()? {
+      let mut index = index?;
+      while next.as_ref().is_some_and(|m| m.from < index.offset) {
+        next = runs::read_record(&mut moves)?;
+      }
+      // not copied, a tombstone or a version past keep_versions is not needed anymore
+      if let Some(m) = next.as_ref().filter(|m| m.from == index.offset) {
+        index.offset = m.to;
+        copied.push(index)?;
+      }
+    }
+    Ok(copied)
+  }
+
+  /// copy the needles appended since start, then swap the volumes
+  fn finish(mut self, index_file: &Mutex<IndexFile>) -> io::Result<CompactReport> {
+    let mut index_file = index_file.lock().unwrap();
+
+    // taken before the copy: an upload leaving meanwhile has wiped its space already
+    let reserved = index_file.reserved_in(self.volume, self.watermark);
+    let size_before = self.src.seek(io::SeekFrom::End(0))?;
+    let tail_base = self.dst.stream_position()?;
+    self.src.seek(io::SeekFrom::Start(self.watermark))?;
+    io::copy(&mut (&mut self.src).take(size_before - self.watermark), &mut self.dst)?;
+    // the uploads in flight can not finish, their bytes come from the clients and are not copied
+    for (offset, size) in reserved {
+      PhysicalFileItem::wipe(&self.dst, offset - self.watermark + tail_base, size)?;
+    }
+
+    metrics::FSYNC.time(|| self.dst.sync_all())?;
+    let size_after = self.dst.seek(io::SeekFrom::End(0))?;
+
+    // point the indexes to the new volume, in a new index file that is on disk before the swap
+    let volume = self.volume;
+    let watermark = self.watermark;
+    let mut watermarks = index_file.watermarks()?;
+    for w in watermarks.iter_mut().filter(|w| w.volume == volume) {
+      w.offset = size_after;
+    }
+    let copied = self.copied(&index_file)?;
+    let mut live_needles = 0;
+    let index_filename = compacted(&index_file.index_filename);
+    {
+      let expected = index_file.sorted.count() + index_file.memtable.len() as u64;
+      // the needles appended since start keep their place behind the copied ones
+      let others = index_file.merged()?
+        .filter(|index| !matches!(index, Ok(index) if index.volume == volume && index.offset < watermark))
+        .map(|index| index.map(|mut index| {
+          if index.volume == volume {
+            index.offset = index.offset - watermark + tail_base;
+          }
+          index
+        }));
+      let indexes = runs::union(others, copied.merge()?)
+        .inspect(|index| if matches!(index, Ok(index) if index.volume == volume && index.flag) {
+          live_needles += 1;
+        });
+      SortedIndex::write(&index_filename, &watermarks, expected, indexes)?;
+    }
+
+    fs::rename(&self.tmp_filename, &self.physical_filename)?;
+    // the uploads into the old volume are lost
+    index_file.next_epoch(self.volume);
+    index_file.journal.clear()?;
+    fs::rename(&index_filename, &index_file.index_filename)?;
+    index_file.reopen()?;
+
+    // needles deleted while copying must not come back when the volume is indexed again
+    let dst = &mut self.dst;
+    PhysicalFileItem::scan_needles(&mut fs::File::open(&self.physical_filename)?, 0, |mut needle| {
+      let live = index_file.versions(needle.key)?
+        .iter()
+        .any(|index| index.generation == needle.generation && index.volume == volume && index.offset == needle.offset);
+      if !live {
+        needle.flag = false;
+        PhysicalFileItem::sync(&needle, dst)?;
+      }
+      Ok(())
+    })?;
+    metrics::FSYNC.time(|| self.dst.sync_all())?;
+
+    crate::logln!("Compacted ", self.physical_filename, " from ", size_before, " to ", size_after, " bytes");
+    Ok(CompactReport {
+      volume: self.volume,
+      live_needles,
+      size_before,
+      size_after
+    })
+  }
+}
+
+impl Drop for Compaction {
+  fn drop(&mut self) {
+    let _ = fs::remove_file(&self.moved);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::tests::{fresh, restart};
+
+  fn read(index_file: &mut IndexFile, key: u64) -> io::Result<Option<Vec<u8>>> {
+    let cookie = index_file.get(key)?.map(|index| index.cookie).unwrap_or_default();
+    index_file.get_data(key, cookie, None)
+  }
+
+  #[test]
+  fn compact_while_writing() -> io::Result<()> {
+    let dir = "test_compact";
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir)?;
+    let index = format!("{}/index", dir);
+    fs::File::create(&index)?;
+    let volumes = VolumeSet::open(&format!("{}/volume", dir), u64::MAX)?;
+    let volume = volumes.path(0);
+    let index_file = Mutex::new(IndexFile::new(vec![], 4, 0, index, volumes, KeyAllocator::open(&format!("{}/key", dir))?,
+      Journal::open(&format!("{}/journal", dir), usize::MAX)?)?);
+
+    let (a, b, c) = {
+      let mut index_file = index_file.lock().unwrap();
+      let a = index_file.add_item(&Metadata::default(), &[1u8; 1000])?.key;
+      let b = index_file.add_item(&Metadata::default(), &[2u8; 2000])?.key;
+      let c = index_file.add_item(&Metadata::default(), &[3u8; 3000])?.key;
+      index_file.delete_item(b)?;
+      (a, b, c)
+    };
+    let mut early = index_file.lock().unwrap().start_upload(None, &Metadata::default(), 5)?.unwrap();
+    early.write(b"early")?;
+
+    let compaction = Compaction::start(&index_file, 0)?;
+    let (d, e, late) = {
+      // the service keeps going while the live needles are copied
+      let mut index_file = index_file.lock().unwrap();
+      index_file.delete_item(c)?;
+      let d = index_file.add_item(&Metadata::default(), &[4u8; 10])?.key;
+      let e = index_file.add_item(&Metadata::default(), &[5u8; 20])?.key;
+      index_file.delete_item(e)?;
+      // an upload reserved before the compaction started is not copied
+      assert!(is_already_running(&index_file.finish_upload(early).unwrap_err()));
+      let mut late = index_file.start_upload(None, &Metadata::default(), 4)?.unwrap();
+      late.write(b"late")?;
+      (d, e, index_file.finish_upload(late)?.unwrap().key)
+    };
+    let mut pending = index_file.lock().unwrap().start_upload(None, &Metadata::default(), 7)?.unwrap();
+    pending.write(b"pending")?;
+    let report = compaction.finish(&index_file)?;
+    // the runs and the moves are gone
+    let mut files = fs::read_dir(dir)?
+      .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().to_string()))
+      .collect::<io::Result<Vec<String>>>()?;
+    files.sort();
+    assert_eq!(files, vec!["index", "journal", "key", "volume.0"]);
+    // the upload in flight is not copied into the new volume
+    assert!(!fs::read(&volume)?.windows(7).any(|bytes| bytes == b"pending"));
+    assert!(is_already_running(&index_file.lock().unwrap().finish_upload(pending).unwrap_err()));
+    assert_eq!(report.live_needles, 3);
+    assert!(report.size_after < report.size_before);
+    assert_eq!(report.size_after, fs::metadata(&volume)?.len());
+
+    let mut index_file = index_file.lock().unwrap();
+    assert_eq!(read(&mut index_file, a)?, Some(vec![1u8; 1000]));
+    assert_eq!(read(&mut index_file, b)?, None);
+    assert_eq!(read(&mut index_file, c)?, None);
+    assert_eq!(read(&mut index_file, d)?, Some(vec![4u8; 10]));
+    assert_eq!(read(&mut index_file, e)?, None);
+    assert_eq!(read(&mut index_file, late)?, Some(b"late".to_vec()));
+
+    // the volume can still be indexed from scratch
+    let keys: Vec<u64> = PhysicalFileItem::build_index_file(&mut fs::File::open(&volume)?, 0)?.0
+      .iter().map(|index| index.key).collect();
+    assert_eq!(keys, vec![a, d, late]);
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+  }
+
+  #[test]
+  fn crash_before_swap() -> io::Result<()> {
+    let dir = "test_compact_crash_before";
+    let index_file = Mutex::new(fresh(dir)?);
+    let (a, b) = {
+      let mut index_file = index_file.lock().unwrap();
+      let a = index_file.add_item(&Metadata::default(), &[1u8; 100])?;
+      let b = index_file.add_item(&Metadata::default(), &[2u8; 200])?;
+      index_file.delete_item(b.key)?;
+      (a, b)
+    };
+    // the crash comes after the new index file is written, before the volumes are swapped
+    let compaction = Compaction::start(&index_file, 0)?;
+    fs::write(format!("{}/index.compact", dir), b"not finished")?;
+    drop(compaction);
+    drop(index_file);
+
+    let mut index_file = restart(dir)?;
+    assert_eq!(index_file.get_data(a.key, a.cookie, None)?, Some(vec![1u8; 100]));
+    assert_eq!(index_file.get_data(b.key, b.cookie, None)?, None);
+    assert!(fs::metadata(format!("{}/index.compact", dir)).is_err());
+    assert!(fs::metadata(format!("{}/volume.0.compact", dir)).is_err());
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+  }
+
+  #[test]
+  fn crash_after_swap() -> io::Result<()> {
+    let dir = "test_compact_crash_after";
+    let index = format!("{}/index", dir);
+    let journal = format!("{}/journal", dir);
+    let index_file = Mutex::new(fresh(dir)?);
+    let (a, b, c) = {
+      let mut index_file = index_file.lock().unwrap();
+      let a = index_file.add_item(&Metadata::default(), &[1u8; 100])?;
+      let b = index_file.add_item(&Metadata::default(), &[2u8; 200])?;
+      let c = index_file.add_item(&Metadata::default(), &[3u8; 300])?;
+      index_file.delete_item(a.key)?;
+      (a, b, c)
+    };
+    let (old_index, old_journal) = (fs::read(&index)?, fs::read(&journal)?);
+    compact(&index_file)?;
+    drop(index_file);
+    // the crash comes after the volumes are swapped, before the new index file takes its place
+    fs::rename(&index, format!("{}.compact", index))?;
+    fs::write(&index, old_index)?;
+    fs::write(&journal, old_journal)?;
+    assert!(is_unfinished(&index, &VolumeSet::open(&format!("{}/volume", dir), u64::MAX)?));
+
+    let mut index_file = restart(dir)?;
+    assert_eq!(index_file.get_data(a.key, a.cookie, None)?, None);
+    assert_eq!(index_file.get_data(b.key, b.cookie, None)?, Some(vec![2u8; 200]));
+    assert_eq!(index_file.get_data(c.key, c.cookie, None)?, Some(vec![3u8; 300]));
+    assert!(fs::metadata(format!("{}.compact", index)).is_err());
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+  }
+
+  #[test]
+  fn compact_every_volume() -> io::Result<()> {
+    let dir = "test_compact_volumes";
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir)?;
+    fs::File::create(format!("{}/index", dir))?;
+    let volumes = VolumeSet::open(&format!("{}/volume", dir), 4096)?;
+    let index_file = Mutex::new(IndexFile::new(vec![], 4, 0, format!("{}/index", dir), volumes, KeyAllocator::open(&format!("{}/key", dir))?,
+      Journal::open(&format!("{}/journal", dir), usize::MAX)?)?);
+
+    let keys: Vec<u64> = {
+      let mut index_file = index_file.lock().unwrap();
+      let mut keys = vec![];
+      for i in 0..6u8 {
+        keys.push(index_file.add_item(&Metadata::default(), &[i; 1500])?.key);
+      }
+      index_file.delete_item(keys[0])?;
+      index_file.delete_item(keys[5])?;
+      keys
+    };
+
+    let reports = compact(&index_file)?;
+    assert_eq!(reports.iter().map(|r| r.volume).collect::<Vec<u32>>(), vec![0, 1, 2]);
+    assert_eq!(reports.iter().map(|r| r.live_needles).sum::<usize>(), 4);
+
+    let mut index_file = index_file.lock().unwrap();
+    for (i, key) in keys.iter().enumerate().take(5).skip(1) {
+      assert_eq!(read(&mut index_file, *key)?, Some(vec![i as u8; 1500]));
+    }
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+  }
+}
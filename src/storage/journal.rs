@@ -0,0 +1,199 @@
+//! append-only log of the index changes since the last checkpoint
+//!
+//! every add and delete is appended and fsynced here before it is acknowledged,
+//! so the index survives a crash without ``PUT /sync``. On startup the records are
+//! replayed over the index file. A checkpoint stores the whole index into the index file
+//! and empties the journal.
+//!
+//! layout of a record: IndexFileItem | checksum u32 (crc32c of the item)
+//! a delete is recorded as the item with flag == false
+
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+
+use crate::diskio::codec::Codec;
+use crate::diskio::crc32c;
+use crate::diskio::read_write;
+use crate::metrics;
+use super::IndexFileItem;
+
+const RECORD_SIZE: usize = IndexFileItem::SIZE + 4;
+
+#[derive(Debug)]
+pub struct Journal {
+  f: fs::File,
+  records: usize,         // records since the last checkpoint
+  checkpoint_every: usize // a checkpoint is due after this many records
+}
+
+impl Journal {
+  /// open the journal at path for appending, it is created if it does not exist
+  /// replay it first, a torn record at the end would hide the records behind it
+  pub fn open(path: &str, checkpoint_every: usize) -> io::Result<Self> {
+    let f = fs::OpenOptions::new()
+      .append(true)
+      .create(true)
+      .open(path)?;
+    let records = f.metadata()?.len() as usize / RECORD_SIZE;
+
+    Ok(Journal {
+      f,
+      records,
+      checkpoint_every
+    })
+  }
+
+  /// a journal that is never kept, for tests that only look up the in-memory index
+  #[cfg(test)]
+  pub fn detached() -> Self {
+    Journal::open("/dev/null", usize::MAX).unwrap()
+  }
+
+  /// a journal that takes no records, for an index file that is only looked at, see ``IndexFile::inspect``
+  /// it is opened for reading, so an append fails
+  pub fn closed() -> io::Result<Self> {
+    Ok(Journal {
+      f: fs::File::open("/dev/null")?,
+      records: 0,
+      checkpoint_every: usize::MAX
+    })
+  }
+
+  /// read every record of the journal at path, in the order they are written
+  /// a torn or corrupted record ends the journal: it is cut off with everything behind it
+  pub fn replay(path: &str) -> io::Result<Vec<IndexFileItem>> {
+    let mut f = match fs::OpenOptions::new().read(true).write(true).open(path) {
+      Ok(f) => f,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+      Err(e) => return Err(e)
+    };
+    let len = f.metadata()?.len();
+    let (r, valid) = Self::read(&mut f, len)?;
+
+    if valid != len {
+      crate::logln!("Cut off the journal ", path, " from ", len, " to ", valid, " bytes");
+      f.set_len(valid)?;
+      f.sync_all()?;
+    }
+    Ok(r)
+  }
+
+  /// the records ``replay`` returns, without cutting anything off
+  pub fn records(path: &str) -> io::Result<Vec<IndexFileItem>> {
+    let mut f = match fs::File::open(path) {
+      Ok(f) => f,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+      Err(e) => return Err(e)
+    };
+    let len = f.metadata()?.len();
+    Ok(Self::read(&mut f, len)?.0)
+  }
+
+  /// the records of the journal f of len bytes up to the first torn or corrupted one, and where it starts
+  fn read(f: &mut fs::File, len: u64) -> io::Result<(Vec<IndexFileItem>, u64)> {
+    let mut r = vec![];
+    let mut buf = vec![0u8; RECORD_SIZE];
+    let mut valid = 0u64;
+    while valid + RECORD_SIZE as u64 <= len {
+      f.read_exact(&mut buf)?;
+      let (item, checksum) = buf.split_at(IndexFileItem::SIZE);
+      if crc32c::update(0, item) != u32::decode(checksum)? {
+        break;
+      }
+      match IndexFileItem::decode(item) {
+        Ok(item) => r.push(item),
+        Err(_) => break
+      }
+      valid += RECORD_SIZE as u64;
+    }
+    Ok((r, valid))
+  }
+
+  /// append item and wait until it is on disk
+  pub fn append(&mut self, item: &IndexFileItem) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(RECORD_SIZE);
+    item.encode(&mut buf);
+    crc32c::update(0, &buf).encode(&mut buf);
+    read_write::write_bytes_to_file(&buf, &mut self.f)?;
+    metrics::FSYNC.time(|| self.f.sync_data())?;
+    self.records += 1;
+
+    Ok(())
+  }
+
+  pub fn checkpoint_due(&self) -> bool {
+    self.records >= self.checkpoint_every
+  }
+
+  /// drop every record, call it once they are stored in the index file
+  pub fn clear(&mut self) -> io::Result<()> {
+    self.f.set_len(0)?;
+    metrics::FSYNC.time(|| self.f.sync_all())?;
+    self.records = 0;
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn item(key: u64, flag: bool) -> IndexFileItem {
+    IndexFileItem { cookie: 1, key, generation: 1, flag, volume: 0, offset: 64 * key, size: 10 }
+  }
+
+  #[test]
+  fn replay_in_order() -> io::Result<()> {
+    let path = "test_journal";
+    let _ = fs::remove_file(path);
+    assert!(Journal::replay(path)?.is_empty());
+
+    let mut journal = Journal::open(path, 3)?;
+    journal.append(&item(1, true))?;
+    journal.append(&item(2, true))?;
+    assert!(!journal.checkpoint_due());
+    journal.append(&item(1, false))?;
+    assert!(journal.checkpoint_due());
+
+    let r: Vec<(u64, bool)> = Journal::replay(path)?.iter().map(|i| (i.key, i.flag)).collect();
+    assert_eq!(r, vec![(1, true), (2, true), (1, false)]);
+
+    // records are counted again after a restart
+    assert!(Journal::open(path, 3)?.checkpoint_due());
+
+    journal.clear()?;
+    assert!(Journal::replay(path)?.is_empty());
+
+    fs::remove_file(path)?;
+    Ok(())
+  }
+
+  #[test]
+  fn torn_record_is_cut_off() -> io::Result<()> {
+    let path = "test_journal_torn";
+    let _ = fs::remove_file(path);
+    let mut journal = Journal::open(path, 100)?;
+    journal.append(&item(1, true))?;
+    journal.append(&item(2, true))?;
+    journal.append(&item(3, true))?;
+
+    // a crash in the middle of the last append
+    let f = fs::OpenOptions::new().write(true).open(path)?;
+    f.set_len(3 * RECORD_SIZE as u64 - 5)?;
+    let r: Vec<u64> = Journal::replay(path)?.iter().map(|i| i.key).collect();
+    assert_eq!(r, vec![1, 2]);
+    assert_eq!(fs::metadata(path)?.len(), 2 * RECORD_SIZE as u64);
+
+    // a flipped bit in a record
+    let mut f = fs::OpenOptions::new().write(true).open(path)?;
+    f.seek(io::SeekFrom::Start(RECORD_SIZE as u64 + 6))?;
+    f.write_all(&[0xff])?;
+    let r: Vec<u64> = Journal::replay(path)?.iter().map(|i| i.key).collect();
+    assert_eq!(r, vec![1]);
+
+    fs::remove_file(path)?;
+    Ok(())
+  }
+}
@@ -0,0 +1,112 @@
+//! hand out unique keys for new files
+//!
+//! keys are never reused, even across restarts and index rebuilds:
+//! the key file records a high-water mark that is always above every key handed out.
+//! It is written once per KEY_BATCH keys instead of once per key.
+
+use std::fs;
+use std::io;
+
+use crate::diskio::read_write;
+
+/// number of keys reserved with one write of the key file
+const KEY_BATCH: u64 = 1024;
+
+#[derive(Debug, Clone)]
+pub struct KeyAllocator {
+  path: String,
+  next: u64,      // the next key to hand out
+  reserved: u64   // keys below reserved are recorded as used in the key file
+}
+
+impl KeyAllocator {
+  /// load the high-water mark from path, the file is created if it does not exist
+  pub fn open(path: &str) -> io::Result<Self> {
+    let reserved = match fs::File::open(path) {
+      Ok(mut f) => read_write::read_struct_from_file::<u64>(&mut f)?.unwrap_or(0),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+      Err(e) => return Err(e)
+    };
+
+    Ok(KeyAllocator {
+      path: path.to_string(),
+      next: reserved,
+      reserved
+    })
+  }
+
+  /// an allocator that is never written to, for tests of the in-memory index
+  #[cfg(test)]
+  pub fn detached() -> Self {
+    KeyAllocator {
+      path: String::new(),
+      next: 0,
+      reserved: u64::MAX
+    }
+  }
+
+  /// make sure key and every key below it is never handed out
+  /// used for the keys found in the index or the volumes, u64::MAX leaves no key to hand out
+  pub fn skip_past(&mut self, key: u64) {
+    self.next = self.next.max(key.saturating_add(1));
+  }
+
+  pub fn allocate(&mut self) -> io::Result<u64> {
+    if self.next == u64::MAX {
+      return Err(io::Error::new(io::ErrorKind::Other, "no keys left"));
+    }
+    if self.next >= self.reserved {
+      self.reserve(self.next.saturating_add(KEY_BATCH))?;
+    }
+    let key = self.next;
+    self.next += 1;
+    Ok(key)
+  }
+
+  fn reserve(&mut self, reserved: u64) -> io::Result<()> {
+    let tmp = format!("{}.tmp", self.path);
+    let mut f = fs::File::create(&tmp)?;
+    read_write::append_struct_to_file(&reserved, &mut f)?;
+    f.sync_all()?;
+    fs::rename(&tmp, &self.path)?;
+    self.reserved = reserved;
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn keys_survive_restart() -> io::Result<()> {
+    let path = "test_keys";
+    let _ = fs::remove_file(path);
+
+    let mut keys = KeyAllocator::open(path)?;
+    assert_eq!(keys.allocate()?, 0);
+    assert_eq!(keys.allocate()?, 1);
+    keys.skip_past(5000);
+    assert_eq!(keys.allocate()?, 5001);
+
+    // restart without the keys in the index
+    let mut keys = KeyAllocator::open(path)?;
+    assert!(keys.allocate()? > 5001);
+
+    // skipping below the reserved keys changes nothing
+    let mut again = KeyAllocator::open(path)?;
+    again.skip_past(3);
+    assert!(again.allocate()? > 5001);
+
+    let reserved: u64 = read_write::read_struct_from_file(&mut fs::File::open(path)?)?.unwrap();
+    assert!(reserved > 5001);
+
+    // a key at the very end found in a volume does not overflow
+    again.skip_past(u64::MAX);
+    assert!(again.allocate().is_err());
+
+    fs::remove_file(path)?;
+    Ok(())
+  }
+}
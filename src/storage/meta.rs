@@ -0,0 +1,98 @@
+//! what is known about a file besides its data, stored in its needle in front of the data
+//!
+//! layout: entries u32 | (name length u32 | name | value length u32 | value) * entries
+//! the names are lowercase: content-type, filename and the x-heystack-meta-* headers given on upload
+
+use std::io;
+
+use serde::Serialize;
+
+use crate::diskio::codec::{Codec, Reader};
+use super::corrupted;
+
+/// the prefix of the request headers kept with a file and given back with it
+pub const HEADER_PREFIX: &str = "x-heystack-meta-";
+
+const CONTENT_TYPE: &str = "content-type";
+const FILENAME: &str = "filename";
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Metadata {
+  pub content_type: Option<String>,
+  pub filename: Option<String>,     // the original name of the file
+  pub headers: Vec<(String, String)> // the x-heystack-meta-* headers, in the order given
+}
+
+impl Metadata {
+  fn entries(&self) -> impl Iterator<Item = (&str, &str)> {
+    let content_type = self.content_type.as_deref().map(|value| (CONTENT_TYPE, value));
+    let filename = self.filename.as_deref().map(|value| (FILENAME, value));
+    content_type.into_iter()
+      .chain(filename)
+      .chain(self.headers.iter().map(|(name, value)| (name.as_str(), value.as_str())))
+  }
+
+  pub fn encode(&self, buf: &mut Vec<u8>) {
+    (self.entries().count() as u32).encode(buf);
+    for (name, value) in self.entries() {
+      for s in [name, value].iter() {
+        (s.len() as u32).encode(buf);
+        buf.extend_from_slice(s.as_bytes());
+      }
+    }
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut buf = vec![];
+    self.encode(&mut buf);
+    buf
+  }
+
+  pub fn decode(buf: &[u8]) -> io::Result<Self> {
+    let mut reader = Reader::new(buf);
+    let entries = reader.read::<u32>()?;
+    let mut read_str = || -> io::Result<String> {
+      let len = reader.read::<u32>()? as usize;
+      String::from_utf8(reader.read_bytes(len)?.to_vec())
+        .map_err(|_| corrupted("needle metadata is not utf-8".to_string()))
+    };
+
+    let mut meta = Metadata::default();
+    for _ in 0..entries {
+      let (name, value) = (read_str()?, read_str()?);
+      match name.as_str() {
+        CONTENT_TYPE => meta.content_type = Some(value),
+        FILENAME => meta.filename = Some(value),
+        _ => meta.headers.push((name, value))
+      }
+    }
+    if reader.left() != 0 {
+      return Err(corrupted("needle metadata is too long".to_string()));
+    }
+    Ok(meta)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn metadata_fixture() {
+    let meta = Metadata {
+      content_type: Some("text/plain".to_string()),
+      filename: None,
+      headers: vec![("x-heystack-meta-a".to_string(), "b".to_string())]
+    };
+    let mut bytes = vec![2, 0, 0, 0];
+    for s in ["content-type", "text/plain", "x-heystack-meta-a", "b"].iter() {
+      bytes.extend_from_slice(&(s.len() as u32).to_le_bytes());
+      bytes.extend_from_slice(s.as_bytes());
+    }
+    assert_eq!(meta.to_bytes(), bytes);
+    assert_eq!(Metadata::decode(&bytes).unwrap(), meta);
+
+    assert_eq!(Metadata::default().to_bytes(), vec![0, 0, 0, 0]);
+    assert!(Metadata::decode(&bytes[..bytes.len() - 1]).is_err());
+  }
+}
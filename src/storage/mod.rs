@@ -1,118 +1,782 @@
+use std::collections::hash_map::{Entry, RandomState};
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::io;
 use std::io::prelude::*;
+use std::os::unix::fs::FileExt;
 
+use crate::diskio::codec::{Codec, Reader};
+use crate::diskio::crc32c;
 use crate::diskio::read_write;
+use crate::metrics;
 use serde::{Deserialize, Serialize};
 
+pub mod compact;
+pub mod journal;
+pub mod keys;
+pub mod meta;
+mod runs;
+pub mod sorted;
+pub mod volume;
+
+use journal::Journal;
+use keys::KeyAllocator;
+use meta::Metadata;
+use runs::Runs;
+use sorted::SortedIndex;
+use volume::{Superblock, VolumeSet};
+
+/// the error returned when the bytes on disk are not the bytes written
+pub fn corrupted(msg: String) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// check if e is raised by corrupted data on disk
+pub fn is_corrupted(e: &io::Error) -> bool {
+  e.kind() == io::ErrorKind::InvalidData
+}
+
+/// check if a file of size bytes with meta fits into a volume of max_volume_size bytes
+pub fn fits(meta: &Metadata, size: u64, max_volume_size: u64) -> bool {
+  PhysicalFileItem::needle_size((meta.to_bytes().len() as u64).saturating_add(size)) <= max_volume_size
+}
+
+/// every needle starts at a multiple of NEEDLE_ALIGN in the volume
+const NEEDLE_ALIGN: u64 = 8;
+const NEEDLE_HEADER_MAGIC: u32 = 0x4445_454e; // "NEED" on disk
+const NEEDLE_FOOTER_MAGIC: u32 = 0x454c_444e; // "NDLE" on disk
+/// the data of a needle is read in chunks of this many bytes
+const READ_CHUNK: u64 = 64 * 1024;
+/// data up to this many bytes is checked before it is sent, see ``NeedleData::check``
+const CHECK_LIMIT: u64 = 16 * READ_CHUNK;
+
+/// layout on disk: NeedleHeader | metadata | data | NeedleFooter | padding up to NEEDLE_ALIGN
+/// checksum is crc32c of metadata, data, cookie, key, generation, meta_size, size and created
 #[derive(Debug)]
 pub struct PhysicalFileItem {
-  key: u32,         // unique key of file
-  flag: bool,       // true if file valid,
-  size: u64,        // filesize,
-  data: Vec::<u8>,  // filedata,
+  cookie: u32,          // random, must be given to read the file
+  pub key: u64,         // unique key of file
+  pub generation: u32,  // version of the file
+  flag: bool,           // true if file valid,
+  pub size: u64,        // filesize,
+  pub created: u64,     // unix seconds when this version was written
+  pub meta: Metadata,   // content type, filename, ..., see ``meta``
+  pub data: NeedleData, // filedata, read on demand
+}
+
+/// the data of a needle, read from its volume in chunks of READ_CHUNK bytes
+/// the checksum is checked with the last chunk, a mismatch is returned instead of it
+/// small data is read and checked at once by ``check`` instead
+#[derive(Debug)]
+pub struct NeedleData {
+  f: fs::File,
+  header: NeedleHeader,
+  left: u64,        // bytes not read yet
+  crc: u32,         // of the metadata and the data read
+  checksum: u32,    // from the footer
+  verify: bool,     // false once only a part of the data is read
+  checked: Option<Vec<u8>>, // the data left, read by ``check``
+  done: bool
+}
+
+impl Iterator for NeedleData {
+  type Item = io::Result<Vec<u8>>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.done {
+      return None;
+    }
+    if let Some(data) = self.checked.take() {
+      self.done = true;
+      self.left = 0;
+      return Some(data).filter(|data| !data.is_empty()).map(Ok);
+    }
+    if self.left == 0 {
+      self.done = true;
+      if self.verify && PhysicalFileItem::checksum_of(self.crc, &self.header) != self.checksum {
+        return Some(Err(corrupted(format!("checksum mismatch for key {}", self.header.key))));
+      }
+      return None;
+    }
+    let len = READ_CHUNK.min(self.left);
+    match read_write::read_bytes_from_file(len, &mut self.f) {
+      Ok(chunk) => {
+        self.left -= len;
+        self.crc = crc32c::update(self.crc, &chunk);
+        // the last chunk is held back, so a corrupted file never arrives complete
+        if self.left == 0 && self.verify && PhysicalFileItem::checksum_of(self.crc, &self.header) != self.checksum {
+          self.done = true;
+          return Some(Err(corrupted(format!("checksum mismatch for key {}", self.header.key))));
+        }
+        Some(Ok(chunk))
+      },
+      Err(e) => {
+        self.done = true;
+        Some(Err(e))
+      }
+    }
+  }
+}
+
+impl NeedleData {
+  /// bytes of data not read yet
+  pub fn left(&self) -> u64 {
+    self.left
+  }
+
+  /// read and check the whole data now if it is at most CHECK_LIMIT bytes, before anything is read
+  /// a mismatch is found before the answer starts then, not behind a part of the data that is sent already
+  pub fn check(&mut self) -> io::Result<()> {
+    if self.left > CHECK_LIMIT || !self.verify || self.checked.is_some() {
+      return Ok(());
+    }
+    let data = read_write::read_bytes_from_file(self.left, &mut self.f)?;
+    if PhysicalFileItem::checksum_of(crc32c::update(self.crc, &data), &self.header) != self.checksum {
+      return Err(corrupted(format!("checksum mismatch for key {}", self.header.key)));
+    }
+    self.checked = Some(data);
+    Ok(())
+  }
+
+  /// read only len bytes from start on, before anything is read
+  /// the checksum can not be checked then, unless ``check`` read the data before
+  pub fn seek(&mut self, start: u64, len: u64) -> io::Result<()> {
+    if start + len > self.left {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "range is out of the data"));
+    }
+    if let Some(data) = &mut self.checked {
+      data.truncate((start + len) as usize);
+      data.drain(..start as usize);
+      self.left = len;
+      return Ok(());
+    }
+    self.f.seek(io::SeekFrom::Current(start as i64))?;
+    self.left = len;
+    self.verify = false;
+    Ok(())
+  }
+
+  /// all of the data at once
+  #[cfg(test)]
+  pub fn read_all(self) -> io::Result<Vec<u8>> {
+    let mut r = vec![];
+    for chunk in self {
+      r.extend(chunk?);
+    }
+    Ok(r)
+  }
+}
+
+/// a needle written while its data arrives, see ``IndexFile::start_upload``
+/// the data is written without the index, the needle is damaged until it is sealed
+/// by ``IndexFile::finish_upload``, so an upload that is not finished is never indexed
+#[derive(Debug)]
+pub struct Upload {
+  f: fs::File,
+  volume: u32,
+  offset: u64,
+  key: u64,
+  update: bool,     // a new version of key, not a new file
+  epoch: u64,       // of volume when the upload started, see ``IndexFile``
+  meta_size: u32,
+  size: u64,        // of the data, announced when the upload starts
+  written: u64,
+  crc: u32,         // of the metadata and the data written
+  sealed: bool,
+  reservations: Reservations
+}
+
+/// the space taken by the uploads in flight, (volume, offset) -> needle size
+/// an upload gives its space up only after it is sealed or wiped, see ``compact``
+type Reservations = Arc<Mutex<BTreeMap<(u32, u64), u64>>>;
+
+impl Upload {
+  /// write the next chunk of the data
+  pub fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+    if self.written + chunk.len() as u64 > self.size {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "more data than announced"));
+    }
+    let at = self.offset + NeedleHeader::SIZE as u64 + self.meta_size as u64 + self.written;
+    self.f.write_all_at(chunk, at)?;
+    self.written += chunk.len() as u64;
+    self.crc = crc32c::update(self.crc, chunk);
+    Ok(())
+  }
+}
+
+/// an upload that is not sealed leaves no bytes of the client behind
+/// they could hold something that looks like a needle, see ``PhysicalFileItem::build_index_file``
+impl Drop for Upload {
+  fn drop(&mut self) {
+    if !self.sealed {
+      let len = self.meta_size as u64 + self.written;
+      if let Err(e) = PhysicalFileItem::wipe(&self.f, self.offset + NeedleHeader::SIZE as u64, len) {
+        crate::logln!("Failed to wipe the upload of key ", self.key, ": ", e);
+      }
+    }
+    if let Ok(mut reservations) = self.reservations.lock() {
+      reservations.remove(&(self.volume, self.offset));
+    }
+  }
+}
+
+/// the fields in front of the data of every PhysicalFileItem
+///
+/// layout: magic u32 | cookie u32 | key u64 | generation u32 | flag u8 | meta_size u32 | size u64 | created u64
+#[derive(Debug, Clone, PartialEq)]
+struct NeedleHeader {
+  cookie: u32,
+  key: u64,
+  generation: u32,  // 1 for a new file, one more for every update
+  flag: bool,
+  meta_size: u32,   // bytes of metadata in front of the data
+  size: u64,
+  created: u64      // unix seconds
+}
+
+impl Codec for NeedleHeader {
+  const SIZE: usize = 4 + 4 + 8 + 4 + 1 + 4 + 8 + 8;
+
+  fn encode(&self, buf: &mut Vec<u8>) {
+    NEEDLE_HEADER_MAGIC.encode(buf);
+    self.cookie.encode(buf);
+    self.key.encode(buf);
+    self.generation.encode(buf);
+    self.flag.encode(buf);
+    self.meta_size.encode(buf);
+    self.size.encode(buf);
+    self.created.encode(buf);
+  }
+
+  fn decode(buf: &[u8]) -> io::Result<Self> {
+    let mut reader = Reader::new(buf);
+    if reader.read::<u32>()? != NEEDLE_HEADER_MAGIC {
+      return Err(corrupted("bad needle header magic".to_string()));
+    }
+    Ok(NeedleHeader {
+      cookie: reader.read()?,
+      key: reader.read()?,
+      generation: reader.read()?,
+      flag: reader.read()?,
+      meta_size: reader.read()?,
+      size: reader.read()?,
+      created: reader.read()?
+    })
+  }
+}
+
+impl NeedleHeader {
+  /// bytes between the header and the footer
+  fn body_size(&self) -> u64 {
+    (self.meta_size as u64).saturating_add(self.size)
+  }
+}
+
+/// the fields behind the data of every PhysicalFileItem
+///
+/// layout: magic u32 | checksum u32
+#[derive(Debug, Clone, PartialEq)]
+struct NeedleFooter {
+  checksum: u32
+}
+
+impl Codec for NeedleFooter {
+  const SIZE: usize = 4 + 4;
+
+  fn encode(&self, buf: &mut Vec<u8>) {
+    NEEDLE_FOOTER_MAGIC.encode(buf);
+    self.checksum.encode(buf);
+  }
+
+  fn decode(buf: &[u8]) -> io::Result<Self> {
+    let mut reader = Reader::new(buf);
+    if reader.read::<u32>()? != NEEDLE_FOOTER_MAGIC {
+      return Err(corrupted("bad needle footer magic".to_string()));
+    }
+    Ok(NeedleFooter {
+      checksum: reader.read()?
+    })
+  }
 }
 
 impl PhysicalFileItem {
-  pub fn get_from_index(index: &IndexFileItem, f: &mut fs::File) -> io::Result<Option<PhysicalFileItem>> {
+  /// a random cookie for a new needle
+  /// RandomState is seeded from the os, so cookies of different needles can not be guessed from each other
+  fn new_cookie() -> u32 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0));
+    hasher.finish() as u32
+  }
+
+  /// the checksum of a needle from body_crc, the crc32c of its metadata and data
+  /// the header comes last, so it can be settled after the data is written, see ``Upload``
+  /// the flag is left out: it is rewritten in place when the file is deleted
+  fn checksum_of(body_crc: u32, header: &NeedleHeader) -> u32 {
+    let crc = crc32c::update(body_crc, &header.cookie.to_bytes());
+    let crc = crc32c::update(crc, &header.key.to_bytes());
+    let crc = crc32c::update(crc, &header.generation.to_bytes());
+    let crc = crc32c::update(crc, &header.meta_size.to_bytes());
+    let crc = crc32c::update(crc, &header.size.to_bytes());
+    crc32c::update(crc, &header.created.to_bytes())
+  }
+
+  /// bytes taken in the volume by a needle holding body bytes of metadata and data
+  /// u64::MAX if that is more than a u64 holds, as for the header of a damaged needle
+  fn needle_size(body: u64) -> u64 {
+    body.checked_add((NeedleHeader::SIZE + NeedleFooter::SIZE) as u64)
+      .and_then(|unpadded| unpadded.checked_next_multiple_of(NEEDLE_ALIGN))
+      .unwrap_or(u64::MAX)
+  }
+
+  /// open the needle that index points to, f is its volume
+  /// only the header, the metadata and the footer are read, the data is read from f on demand
+  /// return a corrupted error if the framing does not match, see ``NeedleData`` for the checksum
+  pub fn get_from_index(index: &IndexFileItem, mut f: fs::File) -> io::Result<Option<PhysicalFileItem>> {
     f.seek(io::SeekFrom::Start(index.offset))?;
 
-    let key = read_write::read_struct_from_file(f)?;
-    let flag = read_write::read_struct_from_file(f)?;
-    let size = read_write::read_struct_from_file(f)?;
-    if key.is_none() || flag.is_none() || size.is_none() {
-      Ok(None)
-    } else {
-      let data = read_write::read_bytes_from_file(size.unwrap(), f)?;
-      Ok(Some(PhysicalFileItem {
-        key: key.unwrap(),
-        flag: flag.unwrap(),
-        size: size.unwrap(),
-        data
-      }))
+    match read_write::read_struct_from_file::<NeedleHeader>(&mut f)? {
+      Some(header) => {
+        if header.key != index.key || header.cookie != index.cookie || header.generation != index.generation {
+          return Err(corrupted(format!("no needle of key {} at {}", index.key, index.offset)));
+        }
+        let meta = read_write::read_bytes_from_file(header.meta_size as u64, &mut f)?;
+        let data_offset = f.stream_position()?;
+        f.seek(io::SeekFrom::Current(header.size as i64))?;
+        let footer = match read_write::read_struct_from_file::<NeedleFooter>(&mut f)? {
+          Some(footer) => footer,
+          None => return Err(corrupted(format!("needle at {} has no footer", index.offset)))
+        };
+        f.seek(io::SeekFrom::Start(data_offset))?;
+        Ok(Some(PhysicalFileItem {
+          cookie: header.cookie,
+          key: header.key,
+          generation: header.generation,
+          flag: header.flag,
+          size: header.size,
+          created: header.created,
+          meta: Metadata::decode(&meta)?,
+          data: NeedleData {
+            f,
+            left: header.size,
+            crc: crc32c::update(0, &meta),
+            checksum: footer.checksum,
+            header,
+            verify: true,
+            checked: None,
+            done: false
+          }
+        }))
+      },
+      None => Ok(None)
     }
   }
 
+  /// the whole needle that index points to, as it is on disk
+  fn read_raw(index: &IndexFileItem, f: &mut fs::File) -> io::Result<Vec<u8>> {
+    let header = Self::header_of(index, f)?;
+    f.seek(io::SeekFrom::Start(index.offset))?;
+    read_write::read_bytes_from_file(Self::needle_size(header.body_size()), f)
+  }
+
+  /// the header of the needle that index points to
+  fn header_of(index: &IndexFileItem, f: &mut fs::File) -> io::Result<NeedleHeader> {
+    f.seek(io::SeekFrom::Start(index.offset))?;
+    match read_write::read_struct_from_file::<NeedleHeader>(f)? {
+      Some(header) if header.key == index.key && header.generation == index.generation => Ok(header),
+      _ => Err(corrupted(format!("no needle of key {} at {}", index.key, index.offset)))
+    }
+  }
+
+  /// rewrite the flag in the header of the needle that index points to
+  /// OpenOption: read, write
   pub fn sync(index: &IndexFileItem, f: &mut fs::File) -> io::Result<()> {
+    let mut header = Self::header_of(index, f)?;
+    header.flag = index.flag;
     f.seek(io::SeekFrom::Start(index.offset))?;
-    read_write::modify_struct_in_file(&index.key, f)?;
-    read_write::modify_struct_in_file(&index.flag, f)?;
+    read_write::modify_struct_in_file(&header, f)?;
 
     Ok(())
   }
 
+  /// take the space of a needle with meta and size bytes of data at the end of the volume f,
+  /// and write meta into it. The header and the footer are left out until ``seal``,
+  /// so the needle is damaged until then, see ``build_index_file``
   /// OpenOption: write
-  pub fn add_one_file(data: &Vec::<u8>, f: &mut fs::File) -> io::Result<IndexFileItem> {
+  /// return the offset of the needle
+  fn reserve(f: &mut fs::File, meta: &[u8], size: u64) -> io::Result<u64> {
     let offset = f.seek(io::SeekFrom::End(0))?;
-    let size = data.len() as u64;
-    let key = (offset / ::std::mem::size_of::<PhysicalFileItem>() as u64) as u32;
-    let pfi = PhysicalFileItem {
+    let end = offset.checked_add(Self::needle_size((meta.len() as u64).saturating_add(size)))
+      .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "the file is larger than a volume"))?;
+    f.set_len(end)?;
+    f.write_all_at(meta, offset + NeedleHeader::SIZE as u64)?;
+    Ok(offset)
+  }
+
+  /// overwrite len bytes of f at offset with zeros
+  fn wipe(f: &fs::File, offset: u64, len: u64) -> io::Result<()> {
+    let zeros = vec![0u8; READ_CHUNK.min(len) as usize];
+    let mut done = 0;
+    while done < len {
+      let n = (len - done).min(READ_CHUNK);
+      f.write_all_at(&zeros[..n as usize], offset + done)?;
+      done += n;
+    }
+    Ok(())
+  }
+
+  /// write the header and the footer of the needle at offset, once its metadata and data are in place
+  /// body_crc is the crc32c of them
+  fn seal(f: &fs::File, offset: u64, header: &NeedleHeader, body_crc: u32) -> io::Result<()> {
+    let footer = NeedleFooter {
+      checksum: Self::checksum_of(body_crc, header)
+    };
+    f.write_all_at(&footer.to_bytes(), offset + NeedleHeader::SIZE as u64 + header.body_size())?;
+    f.write_all_at(&header.to_bytes(), offset)
+  }
+
+  /// write the whole needle at once
+  /// OpenOption: write
+  /// f is the volume with id volume
+  #[cfg(test)]
+  pub fn add_one_file(
+    volume: u32,
+    key: u64,
+    generation: u32,
+    cookie: u32,
+    meta: &Metadata,
+    data: &[u8],
+    f: &mut fs::File
+  ) -> io::Result<IndexFileItem> {
+    let meta = meta.to_bytes();
+    let offset = Self::reserve(f, &meta, data.len() as u64)?;
+    f.write_all_at(data, offset + NeedleHeader::SIZE as u64 + meta.len() as u64)?;
+    let header = NeedleHeader {
+      cookie,
       key,
+      generation,
       flag: true,
-      size,
-      data: vec![] // use data instead of this.data
+      meta_size: meta.len() as u32,
+      size: data.len() as u64,
+      created: 0
     };
-    read_write::append_struct_to_file(&pfi.key, f)?;
-    read_write::append_struct_to_file(&pfi.flag, f)?;
-    read_write::append_struct_to_file(&pfi.size, f)?;
-    read_write::write_bytes_to_file(data, f)?;
+    Self::seal(f, offset, &header, crc32c::update(crc32c::update(0, &meta), data))?;
 
     Ok(IndexFileItem {
+      cookie,
       key,
+      generation,
       flag: true,
-      size,
+      volume,
+      size: data.len() as u64,
       offset
     })
   }
 
-  fn read_struct_from_file_without_data(f: &mut fs::File) -> io::Result<Option<Self>> {
+  /// read the header of the needle at offset and check its framing
+  /// return None if there is no well-framed needle at offset
+  fn read_frame(offset: u64, end: u64, f: &mut fs::File) -> io::Result<Option<NeedleHeader>> {
+    if offset + NeedleHeader::SIZE as u64 > end {
+      return Ok(None);
+    }
+    f.seek(io::SeekFrom::Start(offset))?;
+    let header = match read_write::read_struct_from_file::<NeedleHeader>(f) {
+      Ok(Some(header)) => header,
+      Ok(None) => return Ok(None),
+      Err(e) if is_corrupted(&e) => return Ok(None),
+      Err(e) => return Err(e)
+    };
+    if header.body_size() > end || offset + Self::needle_size(header.body_size()) > end {
+      return Ok(None);
+    }
+
+    f.seek(io::SeekFrom::Start(offset + NeedleHeader::SIZE as u64 + header.body_size()))?;
+    match read_write::read_struct_from_file::<NeedleFooter>(f) {
+      Ok(Some(_)) => Ok(Some(header)),
+      Ok(None) => Ok(None),
+      Err(e) if is_corrupted(&e) => Ok(None),
+      Err(e) => Err(e)
+    }
+  }
 
-    let key: Option<u32> = read_write::read_struct_from_file(f)?;
-    let flag: Option<bool> = read_write::read_struct_from_file(f)?;
-    let size: Option<u64> = read_write::read_struct_from_file(f)?;
+  /// check the checksum of the needle of header at offset
+  fn verify(offset: u64, header: &NeedleHeader, f: &mut fs::File) -> io::Result<bool> {
+    f.seek(io::SeekFrom::Start(offset + NeedleHeader::SIZE as u64))?;
+    let mut crc = 0;
+    let mut left = header.body_size();
+    while left > 0 {
+      let len = READ_CHUNK.min(left);
+      crc = crc32c::update(crc, &read_write::read_bytes_from_file(len, f)?);
+      left -= len;
+    }
+    match read_write::read_struct_from_file::<NeedleFooter>(f) {
+      Ok(Some(footer)) => Ok(footer.checksum == Self::checksum_of(crc, header)),
+      Ok(None) => Ok(false),
+      Err(e) if is_corrupted(&e) => Ok(false),
+      Err(e) => Err(e)
+    }
+  }
 
-    if key.is_none() || flag.is_none() || size.is_none() {
-      Ok(None)
-    } else {
-      f.seek(std::io::SeekFrom::Current(size.unwrap() as i64))?;
-      Ok(Some(PhysicalFileItem {
-        key: key.unwrap(),
-        flag: flag.unwrap(),
-        size: size.unwrap(),
-        data: vec![] // will not return data
-      }))
+  /// find the next aligned position holding the needle header magic, starting at offset
+  /// return end if there is none
+  fn find_next_header(offset: u64, end: u64, f: &mut fs::File) -> io::Result<u64> {
+    const CHUNK: u64 = 64 * 1024;
+    let magic = NEEDLE_HEADER_MAGIC.to_bytes();
+    let mut offset = offset.div_ceil(NEEDLE_ALIGN) * NEEDLE_ALIGN;
+    f.seek(io::SeekFrom::Start(offset))?;
+    while offset < end {
+      let len = CHUNK.min(end - offset);
+      let chunk = read_write::read_bytes_from_file(len, f)?;
+      for at in (0..chunk.len()).step_by(NEEDLE_ALIGN as usize) {
+        if chunk[at..].starts_with(&magic) {
+          return Ok(offset + at as u64);
+        }
+      }
+      offset += len;
     }
+    Ok(end)
   }
 
-  // build the entire index file based on f
+  // build the index of the volume f, starting at the needle at offset, see ``scan_needles``
+  // return the indexes and the end of the last well-framed needle
   // openoption: read
-  pub fn build_index_file(f: &mut fs::File) -> io::Result<Vec<IndexFileItem>> {
+  pub fn build_index_file(f: &mut fs::File, offset: u64) -> io::Result<(Vec<IndexFileItem>, u64)> {
     let mut r = vec![];
-    while let Some(item) = PhysicalFileItem::read_struct_from_file_without_data(f)? {
-      if item.flag {
-        let ifi = IndexFileItem {
-          key: item.key,
-          flag: true,
-          offset: f.stream_position()?,
-          size: item.size
-        };
+    let end = Self::scan_needles(f, offset, |ifi| {
+      r.push(ifi);
+      Ok(())
+    })?;
+    Ok((r, end))
+  }
+
+  // call each with the index of every needle of the volume f that is not deleted, starting at the needle at offset
+  // needles failing the framing check are skipped
+  // a needle found behind skipped bytes must match its checksum too, it may be data that looks like a needle
+  // return the end of the last well-framed needle
+  // openoption: read
+  fn scan_needles(f: &mut fs::File, offset: u64, mut each: impl FnMut(IndexFileItem) -> io::Result<()>) -> io::Result<u64> {
+    let superblock = Superblock::read_from(f)?;
+    let end = f.seek(io::SeekFrom::End(0))?;
+
+    let mut offset = offset.max(Superblock::SIZE as u64);
+    let mut valid_end = offset;
+    let mut resynced = false;
+    while offset < end {
+      let header = match PhysicalFileItem::read_frame(offset, end, f)? {
+        Some(header) if resynced && !PhysicalFileItem::verify(offset, &header, f)? => None,
+        header => header
+      };
+      match header {
+        Some(header) => {
+          if header.flag {
+            let ifi = IndexFileItem {
+              cookie: header.cookie,
+              key: header.key,
+              generation: header.generation,
+              flag: true,
+              volume: superblock.volume_id,
+              offset,
+              size: header.size
+            };
 
-        crate::loglnf!(ifi);
-        r.push(ifi);
+            crate::loglnf!(ifi);
+            each(ifi)?;
+          }
+          offset += PhysicalFileItem::needle_size(header.body_size());
+          valid_end = offset;
+          resynced = false;
+        },
+        None => {
+          let next = PhysicalFileItem::find_next_header(offset + NEEDLE_ALIGN, end, f)?;
+          crate::logln!("Skip damaged bytes from ", offset, " to ", next);
+          offset = next;
+          resynced = true;
+        }
       }
     }
-    Ok(r)
+    Ok(valid_end)
+  }
+
+  /// index the needles of the volume at path from offset on, like ``recover_volume`` but nothing is cut off
+  pub fn scan_volume(path: &str, offset: u64) -> io::Result<Vec<IndexFileItem>> {
+    let mut f = fs::File::open(path)?;
+    if offset > f.metadata()?.len() {
+      return Err(corrupted(format!("volume {} is shorter than the index file expects, run reload", path)));
+    }
+    Ok(PhysicalFileItem::build_index_file(&mut f, offset)?.0)
+  }
+
+  /// index the needles of the volume at path from offset on
+  /// a torn needle at the end, left by a crash while appending, is cut off
+  /// return the indexes and the new end of the volume
+  pub fn recover_volume(path: &str, offset: u64) -> io::Result<(Vec<IndexFileItem>, u64)> {
+    let mut r = vec![];
+    let end = Self::recover_needles(path, offset, |ifi| {
+      r.push(ifi);
+      Ok(())
+    })?;
+    Ok((r, end))
+  }
+
+  /// like ``recover_volume``, but each is called with every index instead of collecting them
+  pub fn recover_needles(path: &str, offset: u64, each: impl FnMut(IndexFileItem) -> io::Result<()>) -> io::Result<u64> {
+    let mut f = fs::OpenOptions::new()
+      .read(true)
+      .write(true)
+      .open(path)?;
+    let len = f.metadata()?.len();
+    if offset > len {
+      return Err(corrupted(format!("volume {} is shorter than the index file expects, run reload", path)));
+    }
+    let valid_end = PhysicalFileItem::scan_needles(&mut f, offset, each)?;
+    if valid_end < len {
+      crate::logln!("Cut off the torn end of ", path, " from ", len, " to ", valid_end, " bytes");
+      f.set_len(valid_end)?;
+      f.sync_all()?;
+    }
+    Ok(valid_end)
+  }
+}
+
+/// the end of the needles of a volume that are covered by the index file
+/// only the needles behind it are scanned on startup
+///
+/// layout: volume u32 | offset u64
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watermark {
+  pub volume: u32,
+  pub offset: u64
+}
+
+impl Codec for Watermark {
+  const SIZE: usize = 4 + 8;
+
+  fn encode(&self, buf: &mut Vec<u8>) {
+    self.volume.encode(buf);
+    self.offset.encode(buf);
+  }
+
+  fn decode(buf: &[u8]) -> io::Result<Self> {
+    let mut reader = Reader::new(buf);
+    Ok(Watermark {
+      volume: reader.read()?,
+      offset: reader.read()?
+    })
+  }
+}
+
+/// what is known about a file without reading its data, see ``IndexFile::stat_file``
+#[derive(Debug, Serialize)]
+pub struct FileStat {
+  pub key: u64,
+  pub generation: u32,
+  pub flag: bool,
+  pub volume: u32,
+  pub offset: u64,       // of the needle in its volume
+  pub size: u64,         // of the data
+  pub needle_size: u64,  // taken in the volume, with the metadata, the framing and the padding
+  pub created: u64,      // unix seconds when this version was written
+  pub meta: Metadata
+}
+
+impl FileStat {
+  /// describe the needle that index points to, f is its volume, see ``PhysicalFileItem::get_from_index``
+  fn read(index: &IndexFileItem, f: fs::File) -> io::Result<Option<Self>> {
+    match PhysicalFileItem::get_from_index(index, f)? {
+      Some(t) if t.flag => Ok(Some(FileStat {
+        key: t.key,
+        generation: t.generation,
+        flag: t.flag,
+        volume: index.volume,
+        offset: index.offset,
+        size: t.size,
+        needle_size: PhysicalFileItem::needle_size(t.data.header.body_size()),
+        created: t.created,
+        meta: t.meta
+      })),
+      // deleted on disk, but the index is not synced yet
+      _ => Ok(None)
+    }
   }
 }
 
+/// the upper bounds of the sizes of the size histogram of ``Stats``, the last bucket is unbounded
+const SIZE_BUCKETS: [u64; 8] = [1 << 10, 1 << 12, 1 << 14, 1 << 16, 1 << 18, 1 << 20, 1 << 24, 1 << 28];
+
+/// how much of the volumes can still be read and how much is left for compaction, see ``IndexFile::stats``
+/// the bytes are the sizes of the data, the needles take a little more
+#[derive(Debug, Serialize)]
+pub struct Stats {
+  pub live_needles: u64,         // the versions that can be read
+  pub deleted_needles: u64,      // deleted files and versions past keep_versions, until compaction
+  pub live_bytes: u64,
+  pub dead_bytes: u64,
+  pub volume_bytes: u64,         // size of all volume files
+  pub garbage_ratio: f64,        // dead_bytes / (live_bytes + dead_bytes)
+  pub index_mem_bytes: u64,      // taken by the memtable and the cached blocks of the index file
+  pub max_index_mem_bytes: u64,  // see ``max_index_in_mem``
+  pub size_histogram: Vec<SizeBucket>,
+  pub volumes: Vec<VolumeStats>
+}
+
+/// the live files of at most le bytes and more than the bound of the bucket before
+#[derive(Debug, Serialize)]
+pub struct SizeBucket {
+  pub le: Option<u64>, // None for the last bucket
+  pub files: u64
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct VolumeStats {
+  pub volume: u32,
+  pub size: u64,
+  pub live_needles: u64,
+  pub deleted_needles: u64,
+  pub live_bytes: u64,
+  pub dead_bytes: u64
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct IndexFileItem {
-  key: u32,         // unique key of file
+  cookie: u32,      // random, must be given to read the file
+  key: u64,         // unique key of file
+  generation: u32,  // version of the file, see ``NeedleHeader``
   flag: bool,       // true if file valid
+  volume: u32,      // id of the volume holding this file
   offset: u64,      // use seek(offset) to find this file
   size: u64         // filesize
 }
 
+/// layout: cookie u32 | key u64 | generation u32 | flag u8 | volume u32 | offset u64 | size u64
+impl Codec for IndexFileItem {
+  const SIZE: usize = 4 + 8 + 4 + 1 + 4 + 8 + 8;
+
+  fn encode(&self, buf: &mut Vec<u8>) {
+    self.cookie.encode(buf);
+    self.key.encode(buf);
+    self.generation.encode(buf);
+    self.flag.encode(buf);
+    self.volume.encode(buf);
+    self.offset.encode(buf);
+    self.size.encode(buf);
+  }
+
+  fn decode(buf: &[u8]) -> io::Result<Self> {
+    let mut reader = Reader::new(buf);
+    Ok(IndexFileItem {
+      cookie: reader.read()?,
+      key: reader.read()?,
+      generation: reader.read()?,
+      flag: reader.read()?,
+      volume: reader.read()?,
+      offset: reader.read()?,
+      size: reader.read()?
+    })
+  }
+}
+
 impl IndexFileItem {
   /// sync this index file item to physical file item
   /// i,e, (only) delete file will raise this function
@@ -125,146 +789,1590 @@ impl IndexFileItem {
   pub fn file_exists(&self) -> bool {
     self.flag
   }
+
+  /// the key and the generation, the indexes are ordered by them
+  pub fn id(&self) -> (u64, u32) {
+    (self.key, self.generation)
+  }
 }
 
-#[derive(Debug, Clone)]
+/// the versions of a file that can be read, from all of its indexes, the newest first:
+/// the latest one and up to keep older live ones, none once the latest one is deleted
+fn retained(newest_first: impl Iterator<Item = IndexFileItem>, keep: u32) -> Vec<IndexFileItem> {
+  let mut versions = newest_first.peekable();
+  if !versions.peek().is_some_and(|latest| latest.flag) {
+    return vec![];
+  }
+  versions.filter(|index| index.flag).take(keep as usize + 1).collect()
+}
+
+/// the indexes changed since the last checkpoint are kept in memory (the memtable),
+/// all others are in the index file, sorted by key and searched on disk, see ``sorted``
+/// at most max indexes are in memory: the memtable takes one half of them,
+/// the blocks of the index file the other. A full memtable is merged into the index file.
+/// changes reach the disk through the journal first, see ``journal``
+/// every update of a file is a new generation of its key. Unless keep_versions > 0,
+/// the older generation is deleted by the update, otherwise that many of them can still be read
+/// every volume has an epoch, one more whenever a compaction of it starts or ends or the index file is reloaded
+/// an upload started in an older epoch of its volume can not finish, see ``Upload``
+#[derive(Debug)]
 pub struct IndexFile {
-  indexes: Vec<IndexFileItem>,
+  memtable: BTreeMap<(u64, u32), IndexFileItem>, // deleted files are kept with flag == false until merged
+  sorted: SortedIndex,
   max: usize,
+  keep_versions: u32,
+  epochs: HashMap<u32, u64>, // of the volumes, 0 if not there
+  reservations: Reservations, // of the uploads started and not dropped yet
   index_filename: String,
-  physical_filename: String
+  volumes: VolumeSet,
+  keys: KeyAllocator,
+  journal: Journal
 }
 
 impl IndexFile {
+  /// indexes are the changes not merged into the index file yet, see ``recover``
   pub fn new(
     indexes: Vec<IndexFileItem>,
     max: usize,
+    keep_versions: u32,
     index_filename: String,
-    physical_filename: String
-  ) -> Self {
-    crate::logln!("Index File In Memory Build");
-    crate::logln!("  Current: ", indexes.len());
-    crate::logln!("  Max:     ", max);
+    volumes: VolumeSet,
+    keys: KeyAllocator,
+    journal: Journal
+  ) -> io::Result<Self> {
+    let mut index_file = Self::open(max, keep_versions, index_filename, volumes, keys, journal)?;
+    index_file.load(indexes, true)?;
+    Ok(index_file)
+  }
 
-    IndexFile {
-      indexes,
+  /// the index file without the changes not merged into it yet, see ``load``
+  fn open(
+    max: usize,
+    keep_versions: u32,
+    index_filename: String,
+    volumes: VolumeSet,
+    mut keys: KeyAllocator,
+    journal: Journal
+  ) -> io::Result<Self> {
+    let max_blocks = (max / 2) as u64 / sorted::BLOCK_ITEMS;
+    let mut sorted = SortedIndex::open(&index_filename, max_blocks as usize)?;
+    if let Some(key) = sorted.last_key()? {
+      keys.skip_past(key);
+    }
+
+    Ok(IndexFile {
+      memtable: BTreeMap::new(),
+      sorted,
       max,
+      keep_versions,
+      epochs: HashMap::new(),
+      reservations: Arc::new(Mutex::new(BTreeMap::new())),
       index_filename,
-      physical_filename
+      volumes,
+      keys,
+      journal
+    })
+  }
+
+  /// put the changes found by ``recover`` into the memtable, later items overwrite the earlier ones
+  /// if fold is true, a memtable of max / 2 indexes is merged into the index file on the way. The watermarks
+  /// stay and the journal is kept until every change is in, so a crash meanwhile finds all of them again
+  fn load(&mut self, indexes: Vec<IndexFileItem>, fold: bool) -> io::Result<()> {
+    crate::logln!("Index File In Memory Build");
+    crate::logln!("  On disk: ", self.sorted.count());
+    crate::logln!("  Current: ", indexes.len());
+    crate::logln!("  Max:     ", self.max);
+
+    let watermarks = self.sorted.watermarks().to_vec();
+    let mut folded = false;
+    for index in indexes {
+      self.keys.skip_past(index.key);
+      self.memtable.insert(index.id(), index);
+      if fold && self.memtable.len() >= self.max / 2 {
+        self.rewrite(&watermarks)?;
+        folded = true;
+      }
     }
+    if folded {
+      self.checkpoint()?;
+    }
+    Ok(())
   }
 
   /// check index item exists
-  pub fn _exists(&self, key: u32) -> (bool, Option<&IndexFileItem>) {
-    let mut r = false;
-    let mut option = None;
-    for index in &self.indexes {
-      if index.key == key && index.flag == true {
-        r = true;
-        option = Some(index);
-        break;
+  pub fn _exists(&mut self, key: u64) -> io::Result<(bool, Option<IndexFileItem>)> {
+    Ok(match self.get(key)? {
+      Some(index) => (true, Some(index)),
+      None => (false, None)
+    })
+  }
+
+  /// the live index of the latest generation of key
+  pub fn get(&mut self, key: u64) -> io::Result<Option<IndexFileItem>> {
+    let newer = self.memtable.range((key, 0)..=(key, u32::MAX)).next_back().map(|(_, index)| index.clone());
+    let index = match (newer, self.sorted.get(key)?) {
+      (Some(newer), Some(older)) if older.generation > newer.generation => Some(older),
+      (Some(newer), _) => Some(newer),
+      (None, older) => older
+    };
+    Ok(index.filter(|index| index.flag))
+  }
+
+  /// the indexes of every generation of key, the newest first, deleted ones included
+  /// the memtable is newer than the index file
+  fn generations(&mut self, key: u64) -> io::Result<Vec<IndexFileItem>> {
+    let mut r = BTreeMap::new();
+    for index in self.sorted.versions(key)? {
+      r.insert(index.generation, index);
+    }
+    for index in self.memtable.range((key, 0)..=(key, u32::MAX)).map(|(_, index)| index) {
+      r.insert(index.generation, index.clone());
+    }
+    Ok(r.into_values().rev().collect())
+  }
+
+  /// the versions of key that can be read, the newest first, see ``retained``
+  pub fn versions(&mut self, key: u64) -> io::Result<Vec<IndexFileItem>> {
+    let keep = self.keep_versions;
+    Ok(retained(self.generations(key)?.into_iter(), keep))
+  }
+
+  /// all indexes ordered by key and generation, deleted ones included until they are merged
+  fn merged(&self) -> io::Result<impl Iterator<Item = io::Result<IndexFileItem>> + '_> {
+    Ok(sorted::merge(self.sorted.iter()?, self.memtable.values()))
+  }
+
+  /// call each with the indexes of volume that can be read, the versions past keep_versions are left out
+  pub fn live_in_volume(&self, volume: u32, mut each: impl FnMut(IndexFileItem) -> io::Result<()>) -> io::Result<()> {
+    let mut generations = vec![];
+    let mut merged = self.merged()?.peekable();
+    while let Some(index) = merged.next() {
+      let index = index?;
+      let last = !matches!(merged.peek(), Some(Ok(next)) if next.key == index.key);
+      generations.push(index);
+      if last {
+        for index in retained(generations.drain(..).rev(), self.keep_versions) {
+          if index.volume == volume {
+            each(index)?;
+          }
+        }
       }
     }
-    (r, option)
+    Ok(())
   }
 
-  pub fn get_mut(&mut self, key: u32) -> Option<&mut IndexFileItem> {
-    let mut option = None;
-    for index in &mut self.indexes {
-      if index.key == key && index.flag == true {
-        option = Some(index);
-        break;
+  /// count the live and the deleted needles of every volume, only the indexes are read
+  pub fn stats(&self) -> io::Result<Stats> {
+    let mut volumes = BTreeMap::new();
+    for id in self.volumes.ids() {
+      let size = fs::metadata(self.volumes.path(*id))?.len();
+      volumes.insert(*id, VolumeStats { volume: *id, size, ..VolumeStats::default() });
+    }
+    let mut histogram = vec![0u64; SIZE_BUCKETS.len() + 1];
+
+    let mut generations = vec![];
+    let mut merged = self.merged()?.peekable();
+    while let Some(index) = merged.next() {
+      let index = index?;
+      let last = !matches!(merged.peek(), Some(Ok(next)) if next.key == index.key);
+      generations.push(index);
+      if !last {
+        continue;
+      }
+      let live = retained(generations.iter().rev().cloned(), self.keep_versions);
+      for index in generations.drain(..) {
+        let volume = volumes.entry(index.volume)
+          .or_insert_with(|| VolumeStats { volume: index.volume, ..VolumeStats::default() });
+        if live.iter().any(|live| live.generation == index.generation) {
+          volume.live_needles += 1;
+          volume.live_bytes += index.size;
+          histogram[SIZE_BUCKETS.partition_point(|le| *le < index.size)] += 1;
+        } else {
+          volume.deleted_needles += 1;
+          volume.dead_bytes += index.size;
+        }
       }
     }
 
-    option
+    let volumes: Vec<VolumeStats> = volumes.into_values().collect();
+    let live_bytes = volumes.iter().map(|v| v.live_bytes).sum::<u64>();
+    let dead_bytes = volumes.iter().map(|v| v.dead_bytes).sum::<u64>();
+    let index_size = std::mem::size_of::<IndexFileItem>();
+    Ok(Stats {
+      live_needles: volumes.iter().map(|v| v.live_needles).sum(),
+      deleted_needles: volumes.iter().map(|v| v.deleted_needles).sum(),
+      live_bytes,
+      dead_bytes,
+      volume_bytes: volumes.iter().map(|v| v.size).sum(),
+      garbage_ratio: if live_bytes + dead_bytes == 0 { 0.0 } else { dead_bytes as f64 / (live_bytes + dead_bytes) as f64 },
+      index_mem_bytes: self.index_mem_bytes(),
+      max_index_mem_bytes: self.max.saturating_mul(index_size) as u64,
+      size_histogram: histogram.into_iter().enumerate()
+        .map(|(i, files)| SizeBucket { le: SIZE_BUCKETS.get(i).copied(), files })
+        .collect(),
+      volumes
+    })
+  }
+
+  /// the memory taken by the memtable and the cached blocks of the index file
+  fn index_mem_bytes(&self) -> u64 {
+    ((self.memtable.len() + self.sorted.cached_indexes()) * std::mem::size_of::<IndexFileItem>()) as u64
+  }
+
+  /// what ``GET /metrics`` reports about the store, cheaper than ``stats``
+  pub fn gauges(&self) -> io::Result<metrics::Gauges> {
+    let mut volume_bytes = 0;
+    for id in self.volumes.ids() {
+      volume_bytes += fs::metadata(self.volumes.path(*id))?.len();
+    }
+    Ok(metrics::Gauges {
+      indexes: self.sorted.count() + self.memtable.len() as u64,
+      index_mem_bytes: self.index_mem_bytes(),
+      volume_bytes
+    })
   }
 
-  /// delete index file item
+  /// delete index file item, all of its versions
   /// return
   /// Ok(()), delete success
   /// Err(()), no such file
-  pub fn delete_item(&mut self, key: u32) -> io::Result<()> {
+  pub fn delete_item(&mut self, key: u64) -> io::Result<()> {
     crate::logln!("delete item with key ", key);
-    let physical_filename = self.physical_filename.clone();
-    match self.get_mut(key) {
-      None => io::Result::Err(io::Error::new(io::ErrorKind::Other, "No Such File")),
-      Some(item) => {
-        item.flag = false;
-        item.sync(
-          &mut fs::OpenOptions::new()
-            .write(true)
-            .read(true)
-            .open(physical_filename)?
-        )?;
-        Ok(())
-      }
+    let generations = self.generations(key)?;
+    if !generations.first().is_some_and(|latest| latest.flag) {
+      return io::Result::Err(io::Error::new(io::ErrorKind::Other, "No Such File"));
     }
+    // the latest generation first: once it is deleted, the file is gone even if the others are not
+    for item in generations.into_iter().filter(|index| index.flag) {
+      self.remove_needle(item)?;
+    }
+    self.checkpoint_if_due()
   }
 
-  pub fn add_item(&mut self, data: &Vec::<u8>) -> io::Result<IndexFileItem> {
-    let physical_filename = self.physical_filename.clone();
-    let r = PhysicalFileItem::add_one_file(&data,
-      &mut fs::OpenOptions::new()
-        .write(true)
-        .read(true)
-        .open(physical_filename)?
-    )?;
-    self.indexes.push(r.clone());
-    crate::logln!("adding new data with new key ", r.key);
+  /// flag the needle of item as deleted in the journal, the volume and the memtable
+  fn remove_needle(&mut self, mut item: IndexFileItem) -> io::Result<()> {
+    item.flag = false;
+    // the journal is the record of the delete, the volume flag follows it
+    // both must be on disk before the journal is cleared, see ``rebuild``
+    self.journal.append(&item)?;
+    let mut f = fs::OpenOptions::new()
+      .write(true)
+      .read(true)
+      .open(self.volumes.path(item.volume))?;
+    item.sync(&mut f)?;
+    metrics::FSYNC.time(|| f.sync_data())?;
+    // the tombstone is kept in the index file until the needle is compacted away
+    self.memtable.insert(item.id(), item);
+    Ok(())
+  }
 
-    // test if out of memory
-    if self.indexes.len() > self.max {
-      crate::logln!("Out of memory. Store files count: ", self.indexes.len());
+  /// start writing a new file, or a new version of key if it is given, with size bytes of data
+  /// the space of the needle is taken in the writable volume now, the data is written by ``Upload::write``
+  /// return None if there is no such file to update, an InvalidInput error if it does not fit into a volume
+  pub fn start_upload(&mut self, key: Option<u64>, meta: &Metadata, size: u64) -> io::Result<Option<Upload>> {
+    if !fits(meta, size, self.volumes.max_size()) {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "the file is larger than a volume"));
+    }
+    let (key, update) = match key {
+      Some(key) if self.get(key)?.is_none() => return Ok(None),
+      Some(key) => (key, true),
+      // a key of an upload that does not finish is never used again
+      None => (self.keys.allocate()?, false)
+    };
+    let meta = meta.to_bytes();
+    let needle_size = PhysicalFileItem::needle_size((meta.len() as u64).saturating_add(size));
+    let volume = self.volumes.writable_for(needle_size)?;
+    let mut f = fs::OpenOptions::new()
+      .write(true)
+      .read(true)
+      .open(self.volumes.path(volume))?;
+    let offset = PhysicalFileItem::reserve(&mut f, &meta, size)?;
+    self.reservations.lock().unwrap().insert((volume, offset), needle_size);
+    Ok(Some(Upload {
+      f,
+      volume,
+      offset,
+      key,
+      update,
+      epoch: self.epoch(volume),
+      meta_size: meta.len() as u32,
+      size,
+      written: 0,
+      crc: crc32c::update(0, &meta),
+      sealed: false,
+      reservations: self.reservations.clone()
+    }))
+  }
+
+  /// seal the needle of upload once all of its data is written and point its key to it
+  /// an update is a new generation of the key, the key and the cookie stay the same
+  /// return None if the file to update is deleted meanwhile
+  pub fn finish_upload(&mut self, mut upload: Upload) -> io::Result<Option<IndexFileItem>> {
+    if upload.written != upload.size {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "less data than announced"));
+    }
+    if upload.epoch != self.epoch(upload.volume) {
+      return Err(io::Error::new(io::ErrorKind::WouldBlock, "a volume was compacted during the upload"));
+    }
+    // the generation is settled now, another update of the key may have finished meanwhile
+    let old = match upload.update {
+      true => match self.get(upload.key)? {
+        Some(old) => Some(old),
+        None => return Ok(None)
+      },
+      false => None
+    };
+    let header = NeedleHeader {
+      cookie: old.as_ref().map_or_else(PhysicalFileItem::new_cookie, |old| old.cookie),
+      key: upload.key,
+      generation: old.as_ref().map_or(1, |old| old.generation + 1),
+      flag: true,
+      meta_size: upload.meta_size,
+      size: upload.size,
+      created: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+    };
+    PhysicalFileItem::seal(&upload.f, upload.offset, &header, upload.crc)?;
+    upload.sealed = true;
+    // the needle must be on disk before the journal points to it
+    metrics::FSYNC.time(|| upload.f.sync_data())?;
+    let r = IndexFileItem {
+      cookie: header.cookie,
+      key: header.key,
+      generation: header.generation,
+      flag: true,
+      volume: upload.volume,
+      offset: upload.offset,
+      size: header.size
+    };
+    // readers see the old needle until the new one is in the journal and the memtable
+    self.journal.append(&r)?;
+    self.memtable.insert(r.id(), r.clone());
+
+    match old {
+      Some(old) => {
+        if self.keep_versions == 0 {
+          // the old needle is superseded, it is deleted
+          // if that is lost, the newer generation still wins
+          self.remove_needle(old)?;
+        }
+        crate::logln!("updating data of key ", r.key, " to generation ", r.generation);
+      },
+      None => crate::logln!("adding new data with new key ", r.key)
+    }
+    self.checkpoint_if_due()?;
+    Ok(Some(r))
+  }
+
+  /// upload a new file at once
+  #[cfg(test)]
+  pub fn add_item(&mut self, meta: &Metadata, data: &[u8]) -> io::Result<IndexFileItem> {
+    let mut upload = self.start_upload(None, meta, data.len() as u64)?.unwrap();
+    upload.write(data)?;
+    Ok(self.finish_upload(upload)?.unwrap())
+  }
+
+  /// upload a new version of key at once
+  /// return None if there is no such file
+  #[cfg(test)]
+  pub fn update_item(&mut self, key: u64, meta: &Metadata, data: &[u8]) -> io::Result<Option<IndexFileItem>> {
+    match self.start_upload(Some(key), meta, data.len() as u64)? {
+      Some(mut upload) => {
+        upload.write(data)?;
+        self.finish_upload(upload)
+      },
+      None => Ok(None)
     }
-    Ok(r)
   }
 
-  pub fn get_data(&mut self, key: u32) -> io::Result<Option<Vec<u8>>> {
+  /// the needle of key, of the latest version unless a generation is given, see ``versions``
+  /// the volume is opened before this returns, so the data can be read without the index
+  /// None if there is no such file or version or the cookie does not match
+  pub fn open_file(&mut self, key: u64, cookie: u32, version: Option<u32>) -> io::Result<Option<PhysicalFileItem>> {
     crate::logln!("getting data with key ", key);
-    let physical_filename = self.physical_filename.clone();
-    match self.get_mut(key) {
-      None => Ok(None),
+    let index = match version {
+      Some(generation) => self.versions(key)?.into_iter().find(|index| index.generation == generation),
+      None => self.get(key)?
+    };
+    match index {
+      Some(ifi) if ifi.cookie != cookie => Ok(None),
       Some(ifi) => {
-        match PhysicalFileItem::get_from_index(ifi, 
-            &mut fs::File::open(physical_filename)?
-          )? {
+        match PhysicalFileItem::get_from_index(&ifi, fs::File::open(self.volumes.path(ifi.volume))?)? {
             None => Ok(None),
-            Some(t) => Ok(Some(t.data))
+            // deleted on disk, but the index is not synced yet
+            Some(t) if !t.flag || t.cookie != cookie => Ok(None),
+            Some(t) => {
+              crate::logln!("opening ", t.size, " bytes of key ", t.key);
+              Ok(Some(t))
+            }
         }
+      },
+      None => Ok(None)
+    }
+  }
+
+  /// where the latest version of key is and what it is, only its header and metadata are read
+  /// None if there is no such file or the cookie does not match
+  pub fn stat_file(&mut self, key: u64, cookie: u32) -> io::Result<Option<FileStat>> {
+    let ifi = match self.get(key)? {
+      Some(ifi) if ifi.cookie == cookie => ifi,
+      _ => return Ok(None)
+    };
+    FileStat::read(&ifi, fs::File::open(self.volumes.path(ifi.volume))?)
+  }
+
+  /// the files with a key greater than after, in order of key, at most limit of them
+  /// keys are never reused, so the last key of a page is a stable cursor for the next one
+  pub fn list_files(&mut self, after: Option<u64>, limit: usize) -> io::Result<Vec<FileStat>> {
+    let mut r = vec![];
+    let mut volumes = HashMap::new();
+    let from = after.map_or(0, |key| key.saturating_add(1));
+    let mut merged = sorted::merge(self.sorted.iter_from(from)?, self.memtable.range((from, 0)..).map(|(_, index)| index))
+      .skip_while(|index| matches!(index, Ok(index) if index.key < from))
+      .peekable();
+    while r.len() < limit {
+      let index = match merged.next() {
+        Some(index) => index?,
+        None => break
+      };
+      // only the latest generation tells if the file is live
+      if matches!(merged.peek(), Some(Ok(next)) if next.key == index.key) || !index.flag {
+        continue;
+      }
+      let f = match volumes.entry(index.volume) {
+        Entry::Occupied(f) => f.into_mut(),
+        Entry::Vacant(f) => f.insert(fs::File::open(self.volumes.path(index.volume))?)
+      };
+      if let Some(stat) = FileStat::read(&index, f.try_clone()?)? {
+        r.push(stat);
       }
     }
+    Ok(r)
   }
 
-  // store self.indexes into index_filename
-  pub fn store_into_file(&self) -> io::Result<()> {
-    crate::logln!("storing indexes into file");
-    let index_filename = self.index_filename.clone();
-    let mut f = fs::File::create(&index_filename)?;
-    for index in &self.indexes {
-      read_write::append_struct_to_file::<IndexFileItem>(&index, &mut f)?;
+  /// the metadata and all of the data of key, see ``open_file``
+  #[cfg(test)]
+  pub fn get_file(&mut self, key: u64, cookie: u32, version: Option<u32>) -> io::Result<Option<(Metadata, Vec<u8>)>> {
+    match self.open_file(key, cookie, version)? {
+      Some(t) => Ok(Some((t.meta, t.data.read_all()?))),
+      None => Ok(None)
     }
-    f.sync_all()?;
+  }
 
-    // try to load file
-    // let mut f = fs::File::open(&index_filename)?;
-    // let mut v: Vec::<IndexFileItem> = vec![];
-    // while let Some(item) = read_write::read_struct_from_file::<IndexFileItem>(&mut f)? {
-    //   v.push(item);
-    // }
-    // println!("{:?}", v);
-    Ok(())
+  /// the data of key, see ``get_file``
+  #[cfg(test)]
+  pub fn get_data(&mut self, key: u64, cookie: u32, version: Option<u32>) -> io::Result<Option<Vec<u8>>> {
+    Ok(self.get_file(key, cookie, version)?.map(|(_, data)| data))
   }
 
-  // based on the given indexes, create index file and save it to that file
-  pub fn create_index_file_and_save(path: &str, indexes: Vec::<IndexFileItem>) -> io::Result<()> {
+  /// check if key is a file that can be read with cookie, before it is changed
+  pub fn has_file(&mut self, key: u64, cookie: u32) -> io::Result<bool> {
+    Ok(self.get(key)?.is_some_and(|index| index.cookie == cookie))
+  }
+
+  /// the versions of key, see ``versions``
+  /// None if there is no such file or the cookie does not match
+  pub fn get_versions(&mut self, key: u64, cookie: u32) -> io::Result<Option<Vec<IndexFileItem>>> {
+    let versions = self.versions(key)?;
+    Ok(Some(versions).filter(|versions| versions.first().is_some_and(|latest| latest.cookie == cookie)))
+  }
+
+  /// merge the memtable into the index file and empty the journal
+  pub fn checkpoint(&mut self) -> io::Result<()> {
+    let watermarks = self.watermarks()?;
+    self.rewrite(&watermarks)?;
+    self.journal.clear()
+  }
+
+  /// merge the memtable into a new index file with watermarks
+  /// the old index file is replaced only after the new one is on disk
+  fn rewrite(&mut self, watermarks: &[Watermark]) -> io::Result<()> {
+    crate::logln!("storing indexes into file");
+    let tmp_filename = format!("{}.tmp", self.index_filename);
+    let expected = self.sorted.count() + self.memtable.len() as u64;
+    SortedIndex::write(&tmp_filename, watermarks, expected, self.merged()?)?;
+    fs::rename(&tmp_filename, &self.index_filename)?;
+    self.reopen()
+  }
+
+  /// the current end of every volume
+  fn watermarks(&self) -> io::Result<Vec<Watermark>> {
+    self.volumes.ids().iter()
+      .map(|id| Ok(Watermark {
+        volume: *id,
+        offset: fs::metadata(self.volumes.path(*id))?.len()
+      }))
+      .collect()
+  }
+
+  /// open the index file again once a new one took its place, the memtable is in it
+  fn reopen(&mut self) -> io::Result<()> {
+    self.sorted = SortedIndex::open(&self.index_filename, self.sorted.max_blocks())?;
+    self.memtable.clear();
+    Ok(())
+  }
+
+  /// take the indexes of rebuilt, an index file opened again after ``rebuild``
+  /// the uploads in flight can not finish, like the ones overlapping a compaction: ``rebuild`` may cut off their space
+  pub fn replace_with(&mut self, rebuilt: IndexFile) {
+    let epochs = self.volumes.ids().iter().map(|id| (*id, self.epoch(*id) + 1)).collect();
+    let reservations = self.reservations.clone();
+    *self = rebuilt;
+    self.epochs = epochs;
+    self.reservations = reservations;
+  }
+
+  /// the uploads started and not finished or dropped yet
+  /// their space is reserved in the volumes but not indexed, ``rebuild`` would cut it off
+  pub fn uploads_in_flight(&self) -> usize {
+    self.reservations.lock().unwrap().len()
+  }
+
+  /// the (offset, size) of the uploads in flight into volume at or past offset from
+  fn reserved_in(&self, volume: u32, from: u64) -> Vec<(u64, u64)> {
+    self.reservations.lock().unwrap()
+      .range((volume, from)..=(volume, u64::MAX))
+      .map(|((_, offset), size)| (*offset, *size))
+      .collect()
+  }
+
+  fn epoch(&self, volume: u32) -> u64 {
+    self.epochs.get(&volume).copied().unwrap_or(0)
+  }
+
+  /// fail the uploads into volume in flight when they finish, its space is about to move, see ``compact``
+  fn next_epoch(&mut self, volume: u32) {
+    *self.epochs.entry(volume).or_insert(0) += 1;
+  }
+
+  /// make everything durable before the service exits:
+  /// the volumes are flushed first, then the journal is folded into the index file
+  pub fn close(&mut self) -> io::Result<()> {
+    for id in self.volumes.ids() {
+      let f = fs::File::open(self.volumes.path(*id))?;
+      metrics::FSYNC.time(|| f.sync_all())?;
+    }
+    self.checkpoint()
+  }
+
+  fn checkpoint_if_due(&mut self) -> io::Result<()> {
+    if self.journal.checkpoint_due() || self.memtable.len() >= self.max / 2 {
+      self.checkpoint()?;
+    }
+    Ok(())
+  }
+
+  /// everything known about the store after a restart that is not merged into the index file:
+  /// the needles appended behind its watermarks, then the journal
+  /// later items overwrite the earlier ones with the same key and generation
+  pub fn recover(path: &str, volumes: &VolumeSet, journal_path: &str) -> io::Result<Vec<IndexFileItem>> {
+    Self::changes(path, volumes, journal_path, true)
+  }
+
+  /// an index file of the store at path that is only looked at, while the service is not running
+  /// the changes are found like ``recover`` does, but a torn end is not cut off and nothing is written
+  pub fn inspect(path: &str, max: usize, keep_versions: u32, volumes: VolumeSet, keys: KeyAllocator, journal_path: &str) -> io::Result<Self> {
+    if compact::is_unfinished(path, &volumes) {
+      return Err(io::Error::new(io::ErrorKind::Other, "a compaction is not finished, start the service to finish it"));
+    }
+    let indexes = Self::changes(path, &volumes, journal_path, false)?;
+    let mut index_file = Self::open(max, keep_versions, path.to_string(), volumes, keys, Journal::closed()?)?;
+    index_file.load(indexes, false)?;
+    Ok(index_file)
+  }
+
+  /// see ``recover``, the torn ends of the volumes and the journal are cut off if repair is true
+  fn changes(path: &str, volumes: &VolumeSet, journal_path: &str, repair: bool) -> io::Result<Vec<IndexFileItem>> {
+    if repair {
+      compact::recover(path, volumes, journal_path)?;
+    }
+    let watermarks = SortedIndex::open(path, 1)?.watermarks().to_vec();
+    let mut r = vec![];
+    for id in volumes.ids() {
+      // a volume created after the index file is scanned from its beginning
+      let offset = watermarks.iter()
+        .find(|watermark| watermark.volume == *id)
+        .map_or(0, |watermark| watermark.offset);
+      let tail = match repair {
+        true => PhysicalFileItem::recover_volume(&volumes.path(*id), offset)?.0,
+        false => PhysicalFileItem::scan_volume(&volumes.path(*id), offset)?
+      };
+      crate::logln!("Found ", tail.len(), " needle(s) in volume ", id, " behind ", offset);
+      r.extend(tail);
+    }
+
+    // changes since the last checkpoint, including the deletes of the needles above
+    let journal = match repair {
+      true => Journal::replay(journal_path)?,
+      false => Journal::records(journal_path)?
+    };
+    crate::logln!("Replay ", journal.len(), " journal record(s)");
+    r.extend(journal);
+
+    Ok(r)
+  }
+
+  /// build the index file at path again from the volumes, for a lost or broken index file
+  /// the tombstones of the old index file and of the journal win over the volume flags,
+  /// so a delete whose volume flag did not reach the disk stays deleted
+  /// at most max / 2 indexes are kept in memory, the others are sorted in runs next to the index file, see ``runs``
+  pub fn rebuild(path: &str, volumes: &VolumeSet, journal_path: &str, max: usize) -> io::Result<()> {
+    compact::recover(path, volumes, journal_path)?;
+    let mut r = Runs::new(&format!("{}.run", path), max / 2, IndexFileItem::id);
+    let mut watermarks = vec![];
+    for id in volumes.ids() {
+      let offset = PhysicalFileItem::recover_needles(&volumes.path(*id), 0, |index| r.push(index))?;
+      watermarks.push(Watermark { volume: *id, offset });
+    }
+
+    match SortedIndex::open(path, 1).and_then(|old| old.iter()) {
+      Ok(old) => {
+        for index in old {
+          match index {
+            Ok(index) if !index.file_exists() => r.push(index)?,
+            Ok(_) => {},
+            Err(e) => {
+              crate::logln!("Stop reading tombstones of ", path, ": ", e);
+              break;
+            }
+          }
+        }
+      },
+      Err(e) => crate::logln!("Cannot read tombstones of ", path, ": ", e)
+    }
+    for index in Journal::replay(journal_path)? {
+      r.push(index)?;
+    }
+
+    let tmp_filename = format!("{}.tmp", path);
+    Self::create_index_file_and_save(&tmp_filename, &watermarks, r)?;
+    fs::rename(&tmp_filename, path)?;
+    Journal::open(journal_path, usize::MAX)?.clear()
+  }
+
+  // based on the given indexes, create index file and save it to that file
+  // for the same key and generation, the later index wins, tombstones are kept
+  fn create_index_file_and_save(path: &str, watermarks: &[Watermark], indexes: Runs<(u64, u32)>) -> io::Result<()> {
     crate::logln!("create index file and save");
-    let mut f = fs::File::create(path)?;
-    for index in indexes {
-      read_write::append_struct_to_file::<IndexFileItem>(&index, &mut f)?;
+    let expected = indexes.pushed();
+    SortedIndex::write(path, watermarks, expected, indexes.merge()?)?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Instant;
+
+  fn index_file_with(count: u64) -> IndexFile {
+    let indexes = (0..count).map(|key| IndexFileItem {
+      cookie: key as u32,
+      key,
+      generation: 1,
+      flag: true,
+      volume: 0,
+      offset: key * 64,
+      size: 32
+    }).collect();
+    IndexFile::new(indexes, usize::MAX, 0, "/dev/null".to_string(), VolumeSet::detached(), KeyAllocator::detached(), Journal::detached()).unwrap()
+  }
+
+  #[test]
+  fn needle_header_fixture() {
+    let header = NeedleHeader { cookie: 0xdeadbeef, key: 0x0a0b0c0d, generation: 3, flag: true, meta_size: 0x10, size: 0x0102, created: 0x5f5e_1000 };
+    let mut bytes = vec![
+      b'N', b'E', b'E', b'D',
+      0xef, 0xbe, 0xad, 0xde,
+      0x0d, 0x0c, 0x0b, 0x0a, 0, 0, 0, 0,
+      0x03, 0, 0, 0,
+      0x01,
+      0x10, 0, 0, 0,
+      0x02, 0x01, 0, 0, 0, 0, 0, 0,
+      0x00, 0x10, 0x5e, 0x5f, 0, 0, 0, 0
+    ];
+    assert_eq!(header.to_bytes(), bytes);
+    assert_eq!(NeedleHeader::decode(&bytes).unwrap(), header);
+
+    bytes[0] = 0;
+    assert!(is_corrupted(&NeedleHeader::decode(&bytes).unwrap_err()));
+
+    let footer = NeedleFooter { checksum: 0x01020304 };
+    assert_eq!(footer.to_bytes(), vec![b'N', b'D', b'L', b'E', 0x04, 0x03, 0x02, 0x01]);
+  }
+
+  #[test]
+  fn needles_are_aligned() {
+    assert_eq!(PhysicalFileItem::needle_size(0), 56);
+    assert_eq!(PhysicalFileItem::needle_size(7), 56);
+    assert_eq!(PhysicalFileItem::needle_size(8), 64);
+    assert_eq!(PhysicalFileItem::needle_size(u64::MAX - 10), u64::MAX);
+    assert!(fits(&Metadata::default(), 4096 - 64, 4096));
+    assert!(!fits(&Metadata::default(), 4096, 4096));
+    assert!(!fits(&Metadata::default(), u64::MAX, 4096));
+  }
+
+  #[test]
+  fn build_index_file_skips_damaged_needles() -> io::Result<()> {
+    let filename = "test_build_index_file";
+    fs::File::create(filename)?;
+    Superblock::create_if_empty(filename, 0)?;
+    let mut f = fs::OpenOptions::new().read(true).write(true).open(filename)?;
+    let a = PhysicalFileItem::add_one_file(0, 1, 1, 11, &Metadata::default(), b"first", &mut f)?;
+    let b = PhysicalFileItem::add_one_file(0, 2, 1, 12, &Metadata::default(), &[7u8; 100], &mut f)?;
+    let c = PhysicalFileItem::add_one_file(0, 3, 1, 13, &Metadata::default(), b"third", &mut f)?;
+    let d = PhysicalFileItem::add_one_file(0, 4, 1, 14, &Metadata::default(), b"fourth", &mut f)?;
+
+    let offsets = |v: Vec<IndexFileItem>| v.iter().map(|i| i.offset).collect::<Vec<u64>>();
+    assert_eq!(offsets(PhysicalFileItem::build_index_file(&mut f, 0)?.0), vec![a.offset, b.offset, c.offset, d.offset]);
+
+    // break the footer magic of b, behind the empty metadata and the data
+    f.seek(io::SeekFrom::Start(b.offset + NeedleHeader::SIZE as u64 + 4 + b.size))?;
+    read_write::modify_struct_in_file(&0u32, &mut f)?;
+    assert_eq!(offsets(PhysicalFileItem::build_index_file(&mut f, 0)?.0), vec![a.offset, c.offset, d.offset]);
+    assert!(is_corrupted(&PhysicalFileItem::get_from_index(&b, f.try_clone()?).unwrap_err()));
+
+    // c is found behind the damaged bytes, so it must match its checksum as well
+    f.seek(io::SeekFrom::Start(c.offset + NeedleHeader::SIZE as u64 + 4))?;
+    read_write::modify_struct_in_file(&0u8, &mut f)?;
+    assert_eq!(offsets(PhysicalFileItem::build_index_file(&mut f, 0)?.0), vec![a.offset, d.offset]);
+
+    // a file without superblock is rejected
+    f.set_len(0)?;
+    assert!(PhysicalFileItem::build_index_file(&mut f, 0).is_err());
+
+    fs::remove_file(filename)?;
+    Ok(())
+  }
+
+  #[test]
+  fn index_file_item_fixture() {
+    let item = IndexFileItem { cookie: 0x01020304, key: 7, generation: 5, flag: false, volume: 2, offset: 0x100, size: 3 };
+    let bytes = vec![
+      0x04, 0x03, 0x02, 0x01,
+      0x07, 0, 0, 0, 0, 0, 0, 0,
+      0x05, 0, 0, 0,
+      0x00,
+      0x02, 0, 0, 0,
+      0x00, 0x01, 0, 0, 0, 0, 0, 0,
+      0x03, 0, 0, 0, 0, 0, 0, 0
+    ];
+    assert_eq!(item.to_bytes(), bytes);
+    let decoded = IndexFileItem::decode(&bytes).unwrap();
+    assert_eq!(
+      (decoded.cookie, decoded.key, decoded.generation, decoded.flag, decoded.volume, decoded.offset, decoded.size),
+      (0x01020304, 7, 5, false, 2, 0x100, 3)
+    );
+  }
+
+  #[test]
+  fn corrupted_needle_is_detected() -> io::Result<()> {
+    let filename = "test_needle_checksum";
+    let mut f = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(filename)?;
+    let index = PhysicalFileItem::add_one_file(0, 1, 1, 11, &Metadata::default(), b"some bytes", &mut f)?;
+    let pfi = PhysicalFileItem::get_from_index(&index, f.try_clone()?)?.unwrap();
+    assert_eq!(pfi.data.read_all()?, b"some bytes".to_vec());
+
+    // flip one bit of the data, behind the empty metadata
+    let at = index.offset + NeedleHeader::SIZE as u64 + 4 + 3;
+    f.seek(io::SeekFrom::Start(at))?;
+    let b: u8 = read_write::read_struct_from_file(&mut f)?.unwrap();
+    f.seek(io::SeekFrom::Start(at))?;
+    read_write::modify_struct_in_file(&(b ^ 0x10), &mut f)?;
+
+    // the header is fine, the data is not
+    let pfi = PhysicalFileItem::get_from_index(&index, f.try_clone()?)?.unwrap();
+    let e = pfi.data.read_all().unwrap_err();
+    assert!(is_corrupted(&e));
+    // found before anything is sent
+    let mut pfi = PhysicalFileItem::get_from_index(&index, f.try_clone()?)?.unwrap();
+    assert!(is_corrupted(&pfi.data.check().unwrap_err()));
+
+    fs::remove_file(filename)?;
+    Ok(())
+  }
+
+  #[test]
+  fn corrupted_file_never_arrives_complete() -> io::Result<()> {
+    let filename = "test_needle_incomplete";
+    let mut f = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(filename)?;
+    let data = vec![7u8; (CHECK_LIMIT + 10) as usize];
+    let index = PhysicalFileItem::add_one_file(0, 1, 1, 11, &Metadata::default(), &data, &mut f)?;
+    f.seek(io::SeekFrom::Start(index.offset + NeedleHeader::SIZE as u64 + 4 + 3))?;
+    read_write::modify_struct_in_file(&8u8, &mut f)?;
+
+    // too large to be checked before, the last chunk is held back instead
+    let mut pfi = PhysicalFileItem::get_from_index(&index, f.try_clone()?)?.unwrap();
+    pfi.data.check()?;
+    let mut sent = 0;
+    for chunk in pfi.data {
+      match chunk {
+        Ok(chunk) => sent += chunk.len(),
+        Err(e) => assert!(is_corrupted(&e))
+      }
+    }
+    assert!(sent < data.len());
+
+    fs::remove_file(filename)?;
+    Ok(())
+  }
+
+  #[test]
+  fn data_is_read_in_chunks() -> io::Result<()> {
+    let filename = "test_needle_chunks";
+    let mut f = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(filename)?;
+    let data: Vec<u8> = (0..READ_CHUNK * 2 + 10).map(|i| i as u8).collect();
+    let index = PhysicalFileItem::add_one_file(0, 1, 1, 11, &Metadata::default(), &data, &mut f)?;
+
+    let pfi = PhysicalFileItem::get_from_index(&index, f.try_clone()?)?.unwrap();
+    let chunks = pfi.data.collect::<io::Result<Vec<Vec<u8>>>>()?;
+    assert_eq!(chunks.iter().map(|chunk| chunk.len() as u64).collect::<Vec<u64>>(), vec![READ_CHUNK, READ_CHUNK, 10]);
+    assert_eq!(chunks.concat(), data);
+
+    // a part of the data
+    let mut pfi = PhysicalFileItem::get_from_index(&index, f.try_clone()?)?.unwrap();
+    pfi.data.seek(READ_CHUNK - 5, 10)?;
+    assert_eq!(pfi.data.read_all()?, data[READ_CHUNK as usize - 5..READ_CHUNK as usize + 5].to_vec());
+    let mut pfi = PhysicalFileItem::get_from_index(&index, f.try_clone()?)?.unwrap();
+    assert!(pfi.data.seek(data.len() as u64 - 5, 10).is_err());
+    // a part of the data checked before
+    let mut pfi = PhysicalFileItem::get_from_index(&index, f.try_clone()?)?.unwrap();
+    pfi.data.check()?;
+    pfi.data.seek(READ_CHUNK - 5, 10)?;
+    assert_eq!(pfi.data.left(), 10);
+    assert_eq!(pfi.data.read_all()?, data[READ_CHUNK as usize - 5..READ_CHUNK as usize + 5].to_vec());
+
+    fs::remove_file(filename)?;
+    Ok(())
+  }
+
+  #[test]
+  fn cookie_is_required() -> io::Result<()> {
+    let dir = "test_cookie";
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir)?;
+    fs::File::create(format!("{}/index", dir))?;
+    let volumes = VolumeSet::open(&format!("{}/volume", dir), u64::MAX)?;
+    let mut index_file = IndexFile::new(vec![], usize::MAX, 0, format!("{}/index", dir), volumes, KeyAllocator::open(&format!("{}/key", dir))?,
+      Journal::open(&format!("{}/journal", dir), usize::MAX)?)?;
+
+    let a = index_file.add_item(&Metadata::default(), b"a")?;
+    let b = index_file.add_item(&Metadata::default(), b"b")?;
+    assert_ne!(a.cookie, b.cookie);
+    assert_eq!(index_file.get_data(a.key, a.cookie, None)?, Some(b"a".to_vec()));
+    assert_eq!(index_file.get_data(a.key, a.cookie ^ 1, None)?, None);
+    assert_eq!(index_file.get_data(a.key, b.cookie, None)?, None);
+    // updates and deletes check it first
+    assert!(index_file.has_file(a.key, a.cookie)?);
+    assert!(!index_file.has_file(a.key, b.cookie)?);
+    index_file.delete_item(a.key)?;
+    assert!(!index_file.has_file(a.key, a.cookie)?);
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+  }
+
+  #[test]
+  fn metadata_is_kept() -> io::Result<()> {
+    let dir = "test_metadata";
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir)?;
+    let meta = Metadata {
+      content_type: Some("image/png".to_string()),
+      filename: Some("cat.png".to_string()),
+      headers: vec![("x-heystack-meta-owner".to_string(), "alice".to_string())]
+    };
+    let a = {
+      let mut index_file = restart(dir)?;
+      let a = index_file.add_item(&meta, b"png")?;
+      assert_eq!(index_file.get_file(a.key, a.cookie, None)?, Some((meta.clone(), b"png".to_vec())));
+      a
+    };
+
+    let mut index_file = restart(dir)?;
+    assert_eq!(index_file.get_file(a.key, a.cookie, None)?, Some((meta, b"png".to_vec())));
+    // an update replaces the metadata too
+    index_file.update_item(a.key, &Metadata::default(), b"raw")?;
+    assert_eq!(index_file.get_file(a.key, a.cookie, None)?, Some((Metadata::default(), b"raw".to_vec())));
+
+    // the header and the metadata are enough to describe it
+    let stat = index_file.stat_file(a.key, a.cookie)?.unwrap();
+    assert_eq!((stat.generation, stat.size, stat.needle_size, stat.meta), (2, 3, 56, Metadata::default()));
+    assert!(stat.created > 0);
+    assert!(index_file.stat_file(a.key, a.cookie.wrapping_add(1))?.is_none());
+    index_file.delete_item(a.key)?;
+    assert!(index_file.stat_file(a.key, a.cookie)?.is_none());
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+  }
+
+  #[test]
+  fn files_are_listed_in_pages() -> io::Result<()> {
+    let dir = "test_list";
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir)?;
+    let mut index_file = restart(dir)?;
+    let mut keys = vec![];
+    for i in 0..300u64 {
+      keys.push(index_file.add_item(&Metadata::default(), &i.to_le_bytes())?.key);
     }
-    f.sync_all()?;
+    index_file.checkpoint()?;
+
+    // the changes since the checkpoint are listed from the memtable
+    let png = Metadata { content_type: Some("image/png".to_string()), ..Metadata::default() };
+    index_file.update_item(keys[150], &png, b"png")?;
+    index_file.delete_item(keys[7])?;
+    let deleted = keys.remove(7);
+    keys.push(index_file.add_item(&Metadata::default(), b"new")?.key);
 
+    let mut listed = vec![];
+    let mut after = None;
+    loop {
+      let page = index_file.list_files(after, 64)?;
+      assert!(page.len() <= 64);
+      match page.last() {
+        Some(last) => after = Some(last.key),
+        None => break
+      }
+      listed.extend(page);
+    }
+    assert_eq!(listed.iter().map(|stat| stat.key).collect::<Vec<u64>>(), keys);
+    let updated = listed.iter().find(|stat| stat.key == keys[149]).unwrap();
+    assert_eq!((updated.generation, updated.size, &updated.meta), (2, 3, &png));
+
+    // a cursor on a deleted key still works
+    assert_eq!(index_file.list_files(Some(deleted), 1)?[0].key, deleted + 1);
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+  }
+
+  #[test]
+  fn stats_count_live_and_dead_needles() -> io::Result<()> {
+    let dir = "test_stats";
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir)?;
+    let mut index_file = restart_with(dir, usize::MAX, 1)?;
+    let a = index_file.add_item(&Metadata::default(), &[0u8; 100])?.key;
+    index_file.add_item(&Metadata::default(), &[1u8; 2000])?;
+    let c = index_file.add_item(&Metadata::default(), &[2u8; 5000])?.key;
+    index_file.checkpoint()?;
+    // the first version of a is past keep_versions
+    index_file.update_item(a, &Metadata::default(), &[3u8; 10])?;
+    index_file.update_item(a, &Metadata::default(), &[4u8; 10])?;
+    index_file.delete_item(c)?;
+
+    let stats = index_file.stats()?;
+    assert_eq!((stats.live_needles, stats.live_bytes), (3, 2020));
+    assert_eq!((stats.deleted_needles, stats.dead_bytes), (2, 5100));
+    assert_eq!(stats.volume_bytes, fs::metadata(format!("{}/volume.0", dir))?.len());
+    assert!((stats.garbage_ratio - 5100.0 / 7120.0).abs() < 1e-9);
+    let histogram: Vec<u64> = stats.size_histogram.iter().map(|bucket| bucket.files).collect();
+    assert_eq!(histogram, vec![2, 1, 0, 0, 0, 0, 0, 0, 0]);
+
+    // the stats of show, the torn ends of the volume and the journal are left for the service to cut off
+    drop(index_file);
+    let (volume, journal) = (format!("{}/volume.0", dir), format!("{}/journal", dir));
+    for path in [&volume, &journal] {
+      fs::OpenOptions::new().append(true).open(path)?.write_all(&[7u8; 5])?;
+    }
+    let lens = || -> io::Result<(u64, u64)> { Ok((fs::metadata(&volume)?.len(), fs::metadata(&journal)?.len())) };
+    let before = lens()?;
+    let index_file = IndexFile::inspect(&format!("{}/index", dir), usize::MAX, 1, VolumeSet::open(&format!("{}/volume", dir), u64::MAX)?,
+      KeyAllocator::open(&format!("{}/key", dir))?, &journal)?;
+    assert_eq!(index_file.stats()?.live_needles, 3);
+    assert_eq!(lens()?, before);
+
+    fs::remove_dir_all(dir)?;
     Ok(())
   }
+
+  /// what the service loads on startup
+  pub(super) fn restart(dir: &str) -> io::Result<IndexFile> {
+    restart_with(dir, usize::MAX, 0)
+  }
+
+  fn restart_with(dir: &str, max: usize, keep_versions: u32) -> io::Result<IndexFile> {
+    let index = format!("{}/index", dir);
+    if fs::metadata(&index).is_err() {
+      fs::File::create(&index)?;
+    }
+    let volumes = VolumeSet::open(&format!("{}/volume", dir), u64::MAX)?;
+    let indexes = IndexFile::recover(&index, &volumes, &format!("{}/journal", dir))?;
+    IndexFile::new(
+      indexes,
+      max,
+      keep_versions,
+      index,
+      volumes,
+      KeyAllocator::open(&format!("{}/key", dir))?,
+      Journal::open(&format!("{}/journal", dir), 5)?
+    )
+  }
+
+  #[test]
+  fn journal_survives_crash() -> io::Result<()> {
+    let dir = "test_journal_crash";
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir)?;
+
+    let mut items = vec![];
+    {
+      let mut index_file = restart(dir)?;
+      for i in 0..6u8 {
+        items.push(index_file.add_item(&Metadata::default(), &[i; 10])?);
+      }
+      index_file.delete_item(items[1].key)?;
+      index_file.delete_item(items[4].key)?;
+      // crash: no sync, the last checkpoint was after the 5th change
+    }
+    assert!(fs::metadata(format!("{}/journal", dir))?.len() > 0);
+
+    let mut index_file = restart(dir)?;
+    for (i, item) in items.iter().enumerate() {
+      let expected = if i == 1 || i == 4 { None } else { Some(vec![i as u8; 10]) };
+      assert_eq!(index_file.get_data(item.key, item.cookie, None)?, expected);
+    }
+    let next = index_file.add_item(&Metadata::default(), b"next")?;
+    assert!(next.key > items[5].key);
+
+    // a sync folds the journal into the index file
+    index_file.checkpoint()?;
+    assert_eq!(fs::metadata(format!("{}/journal", dir))?.len(), 0);
+    drop(index_file);
+    let index_file = restart(dir)?;
+    assert!(index_file.memtable.is_empty());
+    // 5 files and 2 tombstones
+    assert_eq!(index_file.sorted.count(), 7);
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+  }
+
+  #[test]
+  fn recover_behind_watermark() -> io::Result<()> {
+    let dir = "test_watermark";
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir)?;
+    let volume = format!("{}/volume.0", dir);
+
+    let mut items = vec![];
+    {
+      let mut index_file = restart(dir)?;
+      for i in 0..3u8 {
+        items.push(index_file.add_item(&Metadata::default(), &[i; 10])?);
+      }
+      index_file.checkpoint()?;
+      items.push(index_file.add_item(&Metadata::default(), &[3; 10])?);
+      index_file.delete_item(items[0].key)?;
+    }
+    let sorted = SortedIndex::open(&format!("{}/index", dir), 1)?;
+    assert_eq!(sorted.watermarks(), &[Watermark { volume: 0, offset: items[3].offset }]);
+
+    // the journal is lost and a crash tore the needle being appended
+    let len = fs::metadata(&volume)?.len();
+    fs::remove_file(format!("{}/journal", dir))?;
+    let mut f = fs::OpenOptions::new().append(true).open(&volume)?;
+    f.write_all(&NeedleHeader { cookie: 1, key: 99, generation: 1, flag: true, meta_size: 0, size: 1000, created: 0 }.to_bytes())?;
+    f.write_all(&[9u8; 100])?;
+
+    // only the needles behind the watermark are scanned
+    let (tail, end) = PhysicalFileItem::recover_volume(&volume, items[3].offset)?;
+    assert_eq!(tail.iter().map(|index| index.key).collect::<Vec<u64>>(), vec![items[3].key]);
+    assert_eq!(end, len);
+    assert_eq!(fs::metadata(&volume)?.len(), len);
+
+    // the volume flag of the delete is all that is left of it
+    let mut index_file = restart(dir)?;
+    assert_eq!(index_file.get_data(items[0].key, items[0].cookie, None)?, None);
+    for (i, item) in items.iter().enumerate().skip(1) {
+      assert_eq!(index_file.get_data(item.key, item.cookie, None)?, Some(vec![i as u8; 10]));
+    }
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+  }
+
+  // delete -> crash -> restart: a deleted key must stay gone,
+  // after a restart and after the index file is rebuilt from the volumes
+
+  pub(super) fn fresh(dir: &str) -> io::Result<IndexFile> {
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir)?;
+    restart(dir)
+  }
+
+  /// what ``reload`` does
+  fn rebuild(dir: &str) -> io::Result<()> {
+    let volumes = VolumeSet::open(&format!("{}/volume", dir), u64::MAX)?;
+    // a few indexes per run, so every rebuild spills and merges them
+    IndexFile::rebuild(&format!("{}/index", dir), &volumes, &format!("{}/journal", dir), 4)
+  }
+
+  fn assert_gone(dir: &str, deleted: &IndexFileItem, kept: &IndexFileItem) -> io::Result<()> {
+    let mut index_file = restart(dir)?;
+    assert!(index_file.get(deleted.key)?.is_none());
+    assert_eq!(index_file.get_data(deleted.key, deleted.cookie, None)?, None);
+    assert!(index_file.get_data(kept.key, kept.cookie, None)?.is_some());
+    Ok(())
+  }
+
+  #[test]
+  fn delete_crash_before_checkpoint() -> io::Result<()> {
+    let dir = "test_delete_crash";
+    let (a, b) = {
+      let mut index_file = fresh(dir)?;
+      let a = index_file.add_item(&Metadata::default(), b"a")?;
+      let b = index_file.add_item(&Metadata::default(), b"b")?;
+      index_file.delete_item(a.key)?;
+      (a, b)
+    };
+    assert_gone(dir, &a, &b)?;
+    rebuild(dir)?;
+    assert_gone(dir, &a, &b)?;
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+  }
+
+  #[test]
+  fn delete_crash_after_checkpoint() -> io::Result<()> {
+    let dir = "test_delete_checkpoint";
+    let (a, b) = {
+      let mut index_file = fresh(dir)?;
+      let a = index_file.add_item(&Metadata::default(), b"a")?;
+      let b = index_file.add_item(&Metadata::default(), b"b")?;
+      index_file.checkpoint()?;
+      index_file.delete_item(a.key)?;
+      index_file.checkpoint()?;
+      (a, b)
+    };
+    // the tombstone is in the index file
+    let mut sorted = SortedIndex::open(&format!("{}/index", dir), 1)?;
+    assert_eq!(sorted.get(a.key)?.map(|index| index.flag), Some(false));
+    assert_gone(dir, &a, &b)?;
+    rebuild(dir)?;
+    assert_gone(dir, &a, &b)?;
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+  }
+
+  #[test]
+  fn delete_crash_before_volume_flag() -> io::Result<()> {
+    let dir = "test_delete_flag";
+    let (a, b) = {
+      let mut index_file = fresh(dir)?;
+      let a = index_file.add_item(&Metadata::default(), b"a")?;
+      let b = index_file.add_item(&Metadata::default(), b"b")?;
+      index_file.delete_item(a.key)?;
+      (a, b)
+    };
+    // the crash hit after the journal append, the volume still says the needle is live
+    let volume = format!("{}/volume.0", dir);
+    PhysicalFileItem::sync(&a, &mut fs::OpenOptions::new().read(true).write(true).open(&volume)?)?;
+    assert_eq!(PhysicalFileItem::recover_volume(&volume, 0)?.0.len(), 2);
+
+    assert_gone(dir, &a, &b)?;
+    restart(dir)?.checkpoint()?;
+    assert_gone(dir, &a, &b)?;
+    rebuild(dir)?;
+    assert_gone(dir, &a, &b)?;
+    // rebuilding again keeps the tombstone
+    rebuild(dir)?;
+    assert_gone(dir, &a, &b)?;
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+  }
+
+  #[test]
+  fn delete_crash_after_compaction() -> io::Result<()> {
+    let dir = "test_delete_compact";
+    let (a, b) = {
+      let mut index_file = fresh(dir)?;
+      let a = index_file.add_item(&Metadata::default(), b"a")?;
+      let b = index_file.add_item(&Metadata::default(), b"b")?;
+      index_file.delete_item(a.key)?;
+      let index_file = std::sync::Mutex::new(index_file);
+      compact::compact(&index_file)?;
+      (a, b)
+    };
+    // the needle and its tombstone are gone
+    let mut sorted = SortedIndex::open(&format!("{}/index", dir), 1)?;
+    assert!(sorted.get(a.key)?.is_none());
+    assert_gone(dir, &a, &b)?;
+    rebuild(dir)?;
+    assert_gone(dir, &a, &b)?;
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+  }
+
+  #[test]
+  fn update_keeps_the_key() -> io::Result<()> {
+    let dir = "test_update";
+    let (a, b, updated) = {
+      let mut index_file = fresh(dir)?;
+      let a = index_file.add_item(&Metadata::default(), b"first")?;
+      let b = index_file.add_item(&Metadata::default(), b"other")?;
+      let updated = index_file.update_item(a.key, &Metadata::default(), b"second")?.unwrap();
+      assert_eq!((updated.key, updated.cookie, updated.generation), (a.key, a.cookie, 2));
+      assert!(updated.offset > b.offset);
+      assert_eq!(index_file.get_data(a.key, a.cookie, None)?, Some(b"second".to_vec()));
+      assert!(index_file.update_item(999, &Metadata::default(), b"none")?.is_none());
+      // without versioning only the latest version can be read
+      assert_eq!(index_file.get_data(a.key, a.cookie, Some(1))?, None);
+      assert_eq!(index_file.versions(a.key)?.len(), 1);
+      (a, b, updated)
+    };
+
+    // the old needle is superseded in the volume
+    let volume = format!("{}/volume.0", dir);
+    let offsets: Vec<u64> = PhysicalFileItem::recover_volume(&volume, 0)?.0.iter().map(|index| index.offset).collect();
+    assert_eq!(offsets, vec![b.offset, updated.offset]);
+
+    assert_eq!(restart(dir)?.get_data(a.key, a.cookie, None)?, Some(b"second".to_vec()));
+    // even if that flag is lost, the newer needle wins
+    PhysicalFileItem::sync(&a, &mut fs::OpenOptions::new().read(true).write(true).open(&volume)?)?;
+    rebuild(dir)?;
+    assert_eq!(restart(dir)?.get_data(a.key, a.cookie, None)?, Some(b"second".to_vec()));
+    let mut index_file = restart(dir)?;
+    index_file.checkpoint()?;
+    assert_eq!(index_file.get_data(a.key, a.cookie, None)?, Some(b"second".to_vec()));
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+  }
+
+  #[test]
+  fn unfinished_upload_is_invisible() -> io::Result<()> {
+    let dir = "test_upload_abort";
+    let (a, b, aborted_key) = {
+      let mut index_file = fresh(dir)?;
+      let a = index_file.add_item(&Metadata::default(), b"a")?;
+      // aborted after the first chunk
+      let mut aborted = index_file.start_upload(None, &Metadata::default(), 1000)?.unwrap();
+      aborted.write(&[1; 100])?;
+      let aborted_key = aborted.key;
+      let (aborted_offset, aborted_f) = (aborted.offset, aborted.f.try_clone()?);
+      drop(aborted);
+      // the data of the client is wiped, it could look like a needle
+      let mut written = vec![1; 100];
+      aborted_f.read_exact_at(&mut written, aborted_offset + NeedleHeader::SIZE as u64 + 4)?;
+      assert_eq!(written, vec![0; 100]);
+      let b = index_file.add_item(&Metadata::default(), b"b")?;
+
+      // no space is taken for a size that cannot be stored
+      assert_eq!(index_file.start_upload(None, &Metadata::default(), u64::MAX).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+      let mut short = index_file.start_upload(Some(a.key), &Metadata::default(), 10)?.unwrap();
+      assert_eq!(short.write(&[2; 11]).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+      short.write(&[2; 5])?;
+      assert_eq!(index_file.finish_upload(short).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+      (a, b, aborted_key)
+    };
+
+    let volume = format!("{}/volume.0", dir);
+    let keys: Vec<u64> = PhysicalFileItem::recover_volume(&volume, 0)?.0.iter().map(|index| index.key).collect();
+    assert_eq!(keys, vec![a.key, b.key]);
+    rebuild(dir)?;
+    let mut index_file = restart(dir)?;
+    assert_eq!(index_file.get_data(a.key, a.cookie, None)?, Some(b"a".to_vec()));
+    assert_eq!(index_file.get_data(b.key, b.cookie, None)?, Some(b"b".to_vec()));
+    assert_eq!(index_file.versions(a.key)?.len(), 1);
+    // the key of the aborted upload is not used again
+    assert!(index_file.add_item(&Metadata::default(), b"c")?.key > aborted_key);
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+  }
+
+  #[test]
+  fn upload_across_reload_fails() -> io::Result<()> {
+    let dir = "test_upload_reload";
+    let mut index_file = fresh(dir)?;
+    let a = index_file.add_item(&Metadata::default(), b"a")?;
+    let mut upload = index_file.start_upload(None, &Metadata::default(), 3)?.unwrap();
+    upload.write(b"abc")?;
+    // reload refuses to run now, this is what it would do
+    assert_eq!(index_file.uploads_in_flight(), 1);
+
+    // the service rebuilds its index file while the upload runs
+    rebuild(dir)?;
+    let rebuilt = restart(dir)?;
+    index_file.replace_with(rebuilt);
+    assert!(compact::is_already_running(&index_file.finish_upload(upload).unwrap_err()));
+    assert_eq!(index_file.get_data(a.key, a.cookie, None)?, Some(b"a".to_vec()));
+    assert_eq!(index_file.uploads_in_flight(), 0);
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+  }
+
+  #[test]
+  fn concurrent_uploads() -> io::Result<()> {
+    let dir = "test_upload_concurrent";
+    let mut index_file = fresh(dir)?;
+    let a = index_file.add_item(&Metadata::default(), b"a")?;
+
+    // two updates of a interleaved, the one that finishes last is the latest
+    let mut first = index_file.start_upload(Some(a.key), &Metadata::default(), 5)?.unwrap();
+    let mut second = index_file.start_upload(Some(a.key), &Metadata::default(), 6)?.unwrap();
+    second.write(b"second")?;
+    first.write(b"first")?;
+    let second = index_file.finish_upload(second)?.unwrap();
+    let first = index_file.finish_upload(first)?.unwrap();
+    assert_eq!((second.generation, first.generation), (2, 3));
+    assert!(first.offset < second.offset);
+    assert_eq!(index_file.get_data(a.key, a.cookie, None)?, Some(b"first".to_vec()));
+
+    // an update of a file deleted meanwhile
+    let mut late = index_file.start_upload(Some(a.key), &Metadata::default(), 4)?.unwrap();
+    late.write(b"late")?;
+    index_file.delete_item(a.key)?;
+    assert!(index_file.finish_upload(late)?.is_none());
+    assert!(index_file.start_upload(Some(a.key), &Metadata::default(), 4)?.is_none());
+
+    // an upload into a volume swapped by compaction
+    let mut swapped = index_file.start_upload(None, &Metadata::default(), 4)?.unwrap();
+    swapped.write(b"lost")?;
+    let index_file = std::sync::Mutex::new(index_file);
+    compact::compact(&index_file)?;
+    let mut index_file = index_file.into_inner().unwrap();
+    assert!(compact::is_already_running(&index_file.finish_upload(swapped).unwrap_err()));
+    drop(index_file);
+    assert!(PhysicalFileItem::recover_volume(&format!("{}/volume.0", dir), 0)?.0.is_empty());
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+  }
+
+  #[test]
+  fn versions_are_kept() -> io::Result<()> {
+    let dir = "test_versions";
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir)?;
+    let (a, b) = {
+      let mut index_file = restart_with(dir, usize::MAX, 2)?;
+      let a = index_file.add_item(&Metadata::default(), b"v1")?;
+      let b = index_file.add_item(&Metadata::default(), b"other")?;
+      for data in [b"v2", b"v3"] {
+        index_file.update_item(a.key, &Metadata::default(), data)?;
+      }
+      index_file.checkpoint()?;
+      index_file.update_item(a.key, &Metadata::default(), b"v4")?;
+      (a, b)
+    };
+
+    let generations = |index_file: &mut IndexFile| -> io::Result<Vec<u32>> {
+      Ok(index_file.versions(a.key)?.iter().map(|index| index.generation).collect())
+    };
+    let mut index_file = restart_with(dir, usize::MAX, 2)?;
+    assert_eq!(generations(&mut index_file)?, vec![4, 3, 2]);
+    assert_eq!(index_file.get_data(a.key, a.cookie, None)?, Some(b"v4".to_vec()));
+    assert_eq!(index_file.get_data(a.key, a.cookie, Some(2))?, Some(b"v2".to_vec()));
+    assert_eq!(index_file.get_data(a.key, a.cookie ^ 1, Some(2))?, None);
+    assert!(index_file.get_versions(a.key, a.cookie ^ 1)?.is_none());
+    // past the retention count
+    assert_eq!(index_file.get_data(a.key, a.cookie, Some(1))?, None);
+
+    // compaction drops the needle of the version past the retention count
+    let index_file = std::sync::Mutex::new(index_file);
+    let report = compact::compact(&index_file)?;
+    assert_eq!(report[0].live_needles, 4);
+    let mut index_file = index_file.into_inner().unwrap();
+    assert_eq!(index_file.generations(a.key)?.len(), 3);
+    assert_eq!(index_file.get_data(a.key, a.cookie, Some(2))?, Some(b"v2".to_vec()));
+    drop(index_file);
+    rebuild(dir)?;
+    let mut index_file = restart_with(dir, usize::MAX, 2)?;
+    assert_eq!(generations(&mut index_file)?, vec![4, 3, 2]);
+
+    // a delete removes every version
+    index_file.delete_item(a.key)?;
+    assert!(generations(&mut index_file)?.is_empty());
+    assert_eq!(index_file.get_data(a.key, a.cookie, Some(3))?, None);
+    drop(index_file);
+    rebuild(dir)?;
+    let mut index_file = restart_with(dir, usize::MAX, 2)?;
+    assert!(generations(&mut index_file)?.is_empty());
+    assert_eq!(index_file.get_data(b.key, b.cookie, None)?, Some(b"other".to_vec()));
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+  }
+
+  #[test]
+  fn rebuild_keeps_one_index_per_generation() -> io::Result<()> {
+    let dir = "test_rebuild_runs";
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir)?;
+    let mut needles = vec![];
+    {
+      let mut index_file = restart_with(dir, usize::MAX, 2)?;
+      for i in 0..6u8 {
+        let item = index_file.add_item(&Metadata::default(), &[i; 4])?;
+        needles.push(item.clone());
+        for data in [vec![i; 5], vec![i; 6]] {
+          needles.push(index_file.update_item(item.key, &Metadata::default(), &data)?.unwrap());
+        }
+      }
+      // one tombstone ends up in the index file, the other one in the journal
+      index_file.delete_item(needles[3].key)?;
+      index_file.checkpoint()?;
+      index_file.delete_item(needles[12].key)?;
+    }
+    // the flags of the deletes never reached the volume
+    let volume = format!("{}/volume.0", dir);
+    let mut f = fs::OpenOptions::new().read(true).write(true).open(&volume)?;
+    for needle in needles.iter().filter(|needle| needle.key == needles[3].key || needle.key == needles[12].key) {
+      PhysicalFileItem::sync(needle, &mut f)?;
+    }
+
+    // tombstones and generations are spread over the runs, rebuilding twice merges them again
+    rebuild(dir)?;
+    rebuild(dir)?;
+    let ids: Vec<(u64, u32)> = SortedIndex::open(&format!("{}/index", dir), 1)?
+      .iter()?
+      .map(|index| index.map(|index| index.id()))
+      .collect::<io::Result<_>>()?;
+    assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+
+    let mut index_file = restart_with(dir, usize::MAX, 2)?;
+    for (i, item) in needles.iter().enumerate().step_by(3) {
+      let generations: Vec<u32> = index_file.versions(item.key)?.iter().map(|index| index.generation).collect();
+      if i == 3 || i == 12 {
+        assert!(generations.is_empty());
+        assert_eq!(index_file.get_data(item.key, item.cookie, None)?, None);
+      } else {
+        assert_eq!(generations, vec![3, 2, 1]);
+        assert_eq!(index_file.get_data(item.key, item.cookie, None)?, Some(vec![(i / 3) as u8; 6]));
+      }
+    }
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+  }
+
+  #[test]
+  fn spill_to_index_file() -> io::Result<()> {
+    let dir = "test_spill";
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir)?;
+    let max = 2 * 2 * sorted::BLOCK_ITEMS as usize; // 2 blocks
+
+    let mut items = vec![];
+    {
+      let mut index_file = restart_with(dir, max, 0)?;
+      for i in 0..2000u64 {
+        items.push(index_file.add_item(&Metadata::default(), &i.to_bytes())?);
+        assert!(index_file.memtable.len() < max / 2);
+        if i % 3 == 0 {
+          index_file.delete_item(items[i as usize / 2].key)?;
+        }
+      }
+    }
+
+    let mut index_file = restart_with(dir, max, 0)?;
+    assert!(index_file.sorted.count() > (max / 2) as u64);
+    let mut live = 0;
+    for (i, item) in items.iter().enumerate() {
+      if let Some(data) = index_file.get_data(item.key, item.cookie, None)? {
+        assert_eq!(data, (i as u64).to_bytes());
+        live += 1;
+      }
+      assert!(index_file.memtable.len() + index_file.sorted.cached_blocks() * sorted::BLOCK_ITEMS as usize <= max);
+    }
+    assert_eq!(live, 2000 - 667);
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+  }
+
+  #[test]
+  fn recover_more_than_fits() -> io::Result<()> {
+    let dir = "test_recover_fold";
+    let mut index_file = fresh(dir)?;
+    let mut items = vec![];
+    for i in 0..50u64 {
+      items.push(index_file.add_item(&Metadata::default(), &i.to_bytes())?);
+    }
+    index_file.delete_item(items[7].key)?;
+    drop(index_file);
+    // without the index file every needle is found again, more than the memtable takes
+    fs::File::create(format!("{}/index", dir))?;
+
+    let max = 8;
+    let mut index_file = restart_with(dir, max, 0)?;
+    assert!(index_file.memtable.len() < max / 2);
+    // the tombstone of the delete comes from the journal
+    assert_eq!(index_file.sorted.count(), 50);
+    assert_eq!(fs::metadata(format!("{}/journal", dir))?.len(), 0);
+    for (i, item) in items.iter().enumerate() {
+      let expected = Some(i as u64).filter(|i| *i != 7).map(|i| i.to_bytes());
+      assert_eq!(index_file.get_data(item.key, item.cookie, None)?, expected);
+    }
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+  }
+
+  #[test]
+  fn lookup_by_key() -> io::Result<()> {
+    let mut index_file = index_file_with(1000);
+    assert_eq!(index_file.get(999)?.map(|i| i.offset), Some(999 * 64));
+    assert!(index_file.get(1000)?.is_none());
+
+    index_file.memtable.get_mut(&(10, 1)).unwrap().flag = false;
+    assert!(index_file.get(10)?.is_none());
+    assert!(!index_file._exists(10)?.0);
+    assert!(index_file._exists(11)?.0);
+    Ok(())
+  }
+
+  #[test]
+  fn sorted_by_key() -> io::Result<()> {
+    let index_file = index_file_with(5000);
+    let keys = index_file.merged()?.map(|i| i.map(|i| i.key)).collect::<io::Result<Vec<u64>>>()?;
+    assert_eq!(keys, (0..5000).collect::<Vec<u64>>());
+    Ok(())
+  }
+
+  /// cargo test --release -- --ignored --nocapture bench
+  /// the indexes are in the index file on disk, as after a checkpoint: a lookup goes through
+  /// the bloom filter, the block index and the cached blocks, at most 64K indexes are in memory
+  #[test]
+  #[ignore]
+  fn bench_lookup() -> io::Result<()> {
+    const LOOKUPS: u64 = 1_000_000;
+    let dir = "test_bench_lookup";
+    for count in [1_000u64, 10_000, 100_000, 1_000_000, 4_000_000] {
+      let _ = fs::remove_dir_all(dir);
+      fs::create_dir_all(dir)?;
+      let index = format!("{}/index", dir);
+      fs::File::create(&index)?;
+      let mut index_file = IndexFile::new(vec![], 1 << 16, 0, index, VolumeSet::open(&format!("{}/volume", dir), u64::MAX)?,
+        KeyAllocator::detached(), Journal::open(&format!("{}/journal", dir), usize::MAX)?)?;
+      index_file.memtable = index_file_with(count).memtable;
+      index_file.checkpoint()?;
+
+      let start = Instant::now();
+      let mut found = 0u64;
+      for i in 0..LOOKUPS {
+        let key = i.wrapping_mul(2_654_435_761) % count;
+        if index_file.get(key)?.is_some() {
+          found += 1;
+        }
+      }
+      // a key that is not there is mostly ruled out by the bloom filter
+      for i in 0..LOOKUPS {
+        assert!(index_file.get(count + i)?.is_none());
+      }
+      let elapsed = start.elapsed();
+      assert_eq!(found, LOOKUPS);
+      println!("{:>9} indexes: {:>6.1} ns/lookup", count, elapsed.as_nanos() as f64 / (2 * LOOKUPS) as f64);
+    }
+    fs::remove_dir_all(dir)
+  }
 }
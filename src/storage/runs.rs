@@ -0,0 +1,258 @@
+//! sort more indexes than fit into memory
+//!
+//! the indexes are sorted in runs of at most max of them, a full run is spilled
+//! into a file next to the index file. The runs are merged when the indexes are read back,
+//! the later of the indexes with the same sort key wins, the others are dropped.
+//!
+//! layout of a run file: IndexFileItem..., ordered by the sort key
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+
+use crate::diskio::codec::Codec;
+use super::IndexFileItem;
+
+pub struct Runs<K> {
+  prefix: String,                  // the runs are spilled into "{prefix}.0", "{prefix}.1", ...
+  max: usize,                      // indexes kept in memory before they are spilled
+  key: fn(&IndexFileItem) -> K,
+  pushed: u64,
+  memory: Vec<IndexFileItem>,
+  files: Vec<String>
+}
+
+impl<K: Ord + Clone> Runs<K> {
+  pub fn new(prefix: &str, max: usize, key: fn(&IndexFileItem) -> K) -> Self {
+    Runs {
+      prefix: prefix.to_string(),
+      max: max.max(1),
+      key,
+      pushed: 0,
+      memory: vec![],
+      files: vec![]
+    }
+  }
+
+  pub fn push(&mut self, index: IndexFileItem) -> io::Result<()> {
+    self.memory.push(index);
+    self.pushed += 1;
+    if self.memory.len() >= self.max {
+      self.spill()?;
+    }
+    Ok(())
+  }
+
+  /// the indexes pushed, the ones with the same sort key included
+  pub fn pushed(&self) -> u64 {
+    self.pushed
+  }
+
+  /// the indexes in memory sorted, only the last one of every sort key is kept
+  fn sorted(&mut self) -> Vec<IndexFileItem> {
+    let key = self.key;
+    // stable, the later index stays behind the earlier one
+    self.memory.sort_by_key(key);
+    let mut r: Vec<IndexFileItem> = Vec::with_capacity(self.memory.len());
+    for index in self.memory.drain(..) {
+      match r.last_mut() {
+        Some(last) if key(last) == key(&index) => *last = index,
+        _ => r.push(index)
+      }
+    }
+    r
+  }
+
+  fn spill(&mut self) -> io::Result<()> {
+    let path = format!("{}.{}", self.prefix, self.files.len());
+    let mut w = io::BufWriter::new(fs::File::create(&path)?);
+    self.files.push(path);
+    for index in self.sorted() {
+      w.write_all(&index.to_bytes())?;
+    }
+    w.flush()
+  }
+
+  /// every index pushed in the order of the sort key, see ``Runs``
+  /// the run files are removed once the merge is dropped
+  pub fn merge(mut self) -> io::Result<Merge<K>> {
+    if !self.files.is_empty() && !self.memory.is_empty() {
+      self.spill()?;
+    }
+    let memory = if self.files.is_empty() { self.sorted() } else { vec![] };
+    let mut merge = Merge {
+      key: self.key,
+      runs: vec![],
+      heads: vec![],
+      heap: BinaryHeap::new(),
+      memory: memory.into_iter(),
+      files: std::mem::take(&mut self.files)
+    };
+    for path in &merge.files {
+      merge.runs.push(io::BufReader::new(fs::File::open(path)?));
+      merge.heads.push(None);
+    }
+    for run in 0..merge.runs.len() {
+      merge.advance(run)?;
+    }
+    Ok(merge)
+  }
+}
+
+impl<K> Drop for Runs<K> {
+  fn drop(&mut self) {
+    for path in &self.files {
+      let _ = fs::remove_file(path);
+    }
+  }
+}
+
+/// see ``Runs::merge``
+pub struct Merge<K> {
+  key: fn(&IndexFileItem) -> K,
+  runs: Vec<io::BufReader<fs::File>>,
+  heads: Vec<Option<IndexFileItem>>,     // the next index of every run
+  heap: BinaryHeap<Reverse<(K, Reverse<usize>)>>, // the smallest key first, of the later run for the same key
+  memory: std::vec::IntoIter<IndexFileItem>, // all of the indexes if none was spilled
+  files: Vec<String>
+}
+
+impl<K: Ord + Clone> Merge<K> {
+  /// read the next index of run into heads
+  fn advance(&mut self, run: usize) -> io::Result<()> {
+    self.heads[run] = read_record(&mut self.runs[run])?;
+    if let Some(index) = &self.heads[run] {
+      self.heap.push(Reverse(((self.key)(index), Reverse(run))));
+    }
+    Ok(())
+  }
+
+  fn take(&mut self) -> io::Result<Option<IndexFileItem>> {
+    let Reverse((key, Reverse(run))) = match self.heap.pop() {
+      Some(head) => head,
+      None => return Ok(None)
+    };
+    let index = self.heads[run].take();
+    self.advance(run)?;
+    // the earlier runs hold older indexes of the same key
+    while let Some(Reverse((next, Reverse(older)))) = self.heap.peek().cloned() {
+      if next != key {
+        break;
+      }
+      self.heap.pop();
+      self.advance(older)?;
+    }
+    Ok(index)
+  }
+}
+
+impl<K: Ord + Clone> Iterator for Merge<K> {
+  type Item = io::Result<IndexFileItem>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.runs.is_empty() {
+      return self.memory.next().map(Ok);
+    }
+    self.take().transpose()
+  }
+}
+
+impl<K> Drop for Merge<K> {
+  fn drop(&mut self) {
+    for path in &self.files {
+      let _ = fs::remove_file(path);
+    }
+  }
+}
+
+/// read one record from r, None at its end
+pub fn read_record<T: Codec>(r: &mut impl Read) -> io::Result<Option<T>> {
+  let mut buf = vec![0u8; T::SIZE];
+  let mut filled = 0;
+  while filled < T::SIZE {
+    match r.read(&mut buf[filled..])? {
+      0 if filled == 0 => return Ok(None),
+      0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "record is truncated")),
+      n => filled += n
+    }
+  }
+  Ok(Some(T::decode(&buf)?))
+}
+
+/// merge a and b, both ordered by key and generation into one, b wins for the same key and generation
+pub fn union<A, B>(a: A, b: B) -> impl Iterator<Item = io::Result<IndexFileItem>>
+where
+  A: Iterator<Item = io::Result<IndexFileItem>>,
+  B: Iterator<Item = io::Result<IndexFileItem>>
+{
+  let mut a = a.peekable();
+  let mut b = b.peekable();
+  std::iter::from_fn(move || {
+    let a_id = match a.peek() {
+      Some(Ok(index)) => Some(index.id()),
+      Some(Err(_)) => return a.next(),
+      None => None
+    };
+    let b_id = match b.peek() {
+      Some(Ok(index)) => Some(index.id()),
+      Some(Err(_)) => return b.next(),
+      None => None
+    };
+    match (a_id, b_id) {
+      (Some(x), Some(y)) if x < y => a.next(),
+      (Some(x), Some(y)) => {
+        if x == y {
+          a.next();
+        }
+        b.next()
+      },
+      (Some(_), None) => a.next(),
+      (None, _) => b.next()
+    }
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn item(key: u64, generation: u32, offset: u64) -> IndexFileItem {
+    IndexFileItem { cookie: 1, key, generation, flag: true, volume: 0, offset, size: 1 }
+  }
+
+  fn sorted(runs: Runs<(u64, u32)>) -> io::Result<Vec<(u64, u32, u64)>> {
+    runs.merge()?
+      .map(|index| index.map(|index| (index.key, index.generation, index.offset)))
+      .collect()
+  }
+
+  #[test]
+  fn later_index_wins() -> io::Result<()> {
+    for max in [1, 2, 3, 100] {
+      let prefix = format!("test_runs_{}", max);
+      let mut runs = Runs::new(&prefix, max, IndexFileItem::id);
+      for (key, generation, offset) in [(5, 1, 1), (3, 1, 2), (5, 2, 3), (3, 1, 4), (1, 1, 5), (5, 1, 6), (3, 1, 7)] {
+        runs.push(item(key, generation, offset))?;
+      }
+      assert_eq!(runs.pushed(), 7);
+      assert_eq!(sorted(runs)?, vec![(1, 1, 5), (3, 1, 7), (5, 1, 6), (5, 2, 3)]);
+      // nothing is left behind
+      assert!(fs::metadata(format!("{}.0", prefix)).is_err());
+    }
+    assert!(sorted(Runs::new("test_runs_empty", 1, IndexFileItem::id))?.is_empty());
+    Ok(())
+  }
+
+  #[test]
+  fn union_of_sorted() -> io::Result<()> {
+    let a = vec![item(1, 1, 1), item(3, 1, 1), item(4, 1, 1)];
+    let b = vec![item(2, 1, 2), item(3, 1, 2), item(5, 1, 2)];
+    let r: Vec<(u64, u64)> = union(a.into_iter().map(Ok), b.into_iter().map(Ok))
+      .map(|index| index.map(|index| (index.key, index.offset)))
+      .collect::<io::Result<_>>()?;
+    assert_eq!(r, vec![(1, 1), (2, 2), (3, 2), (4, 1), (5, 2)]);
+    Ok(())
+  }
+}
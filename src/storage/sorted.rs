@@ -0,0 +1,602 @@
+//! the index file: indexes sorted by key and generation, searched on disk
+//!
+//! the indexes are stored in blocks of BLOCK_SIZE bytes. The first key of every block
+//! (the block index) and a bloom filter of all keys are kept in memory, the blocks are read
+//! on demand. Only max_blocks of them are kept in memory, the least recently used block
+//! is dropped first, so the index file may hold more keys than the memory allows.
+//!
+//! layout of the file:
+//!   Block * blocks | first key u64 * blocks | bloom filter u64 * words | Watermark * watermarks | Footer
+//! layout of a block:
+//!   IndexFileItem * BLOCK_ITEMS (the last block may hold less) | zero padding | checksum u32 (crc32c of the rest)
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::iter::Peekable;
+
+use crate::diskio::codec::{Codec, Reader};
+use crate::diskio::crc32c;
+use crate::diskio::read_write;
+use crate::metrics;
+use super::{corrupted, IndexFileItem, Watermark};
+
+const INDEX_MAGIC: u32 = 0x5844_4948; // "HIDX" on disk
+/// bumped whenever the layout of the index file changes
+const INDEX_VERSION: u32 = 2;
+const BLOCK_SIZE: u64 = 4096;
+/// number of indexes in a full block
+pub const BLOCK_ITEMS: u64 = (BLOCK_SIZE - 4) / IndexFileItem::SIZE as u64;
+/// bits of the bloom filter per key, about 1% false positives
+const BLOOM_BITS_PER_KEY: u64 = 10;
+const BLOOM_HASHES: u32 = 7;
+
+/// the last bytes of the index file
+///
+/// layout: magic u32 | version u32 | count u64 | blocks u64 | bloom_words u64 | bloom_hashes u32
+///   | watermarks u32 | checksum u32 (crc32c of the block index, the bloom filter, the watermarks and the fields above)
+#[derive(Debug, Clone, PartialEq)]
+struct Footer {
+  count: u64,
+  blocks: u64,
+  bloom_words: u64,
+  bloom_hashes: u32,
+  watermarks: u32,
+  checksum: u32
+}
+
+impl Codec for Footer {
+  const SIZE: usize = 4 + 4 + 8 + 8 + 8 + 4 + 4 + 4;
+
+  fn encode(&self, buf: &mut Vec<u8>) {
+    INDEX_MAGIC.encode(buf);
+    INDEX_VERSION.encode(buf);
+    self.count.encode(buf);
+    self.blocks.encode(buf);
+    self.bloom_words.encode(buf);
+    self.bloom_hashes.encode(buf);
+    self.watermarks.encode(buf);
+    self.checksum.encode(buf);
+  }
+
+  fn decode(buf: &[u8]) -> io::Result<Self> {
+    let mut reader = Reader::new(buf);
+    if reader.read::<u32>()? != INDEX_MAGIC {
+      return Err(corrupted("not a heystack index file, run reload".to_string()));
+    }
+    let version = reader.read::<u32>()?;
+    if version != INDEX_VERSION {
+      return Err(corrupted(format!("unsupported index file version {}, run reload", version)));
+    }
+    Ok(Footer {
+      count: reader.read()?,
+      blocks: reader.read()?,
+      bloom_words: reader.read()?,
+      bloom_hashes: reader.read()?,
+      watermarks: reader.read()?,
+      checksum: reader.read()?
+    })
+  }
+}
+
+impl Footer {
+  /// the bytes between the blocks and the footer
+  fn meta_size(&self) -> u64 {
+    self.blocks * 8 + self.bloom_words * 8 + self.watermarks as u64 * Watermark::SIZE as u64
+  }
+}
+
+/// a set of keys that may answer "maybe" for a key that is not in it, but never "no" for one that is
+#[derive(Debug)]
+struct Bloom {
+  bits: Vec<u64>,
+  hashes: u32
+}
+
+impl Bloom {
+  fn new(expected: u64) -> Self {
+    Bloom {
+      bits: vec![0; (expected * BLOOM_BITS_PER_KEY).div_ceil(64).max(1) as usize],
+      hashes: BLOOM_HASHES
+    }
+  }
+
+  /// the bits of key, from two hashes of it
+  fn positions(&self, key: u64) -> impl Iterator<Item = u64> {
+    let len = self.bits.len() as u64 * 64;
+    let h1 = mix(key);
+    let h2 = mix(h1) | 1;
+    (0..self.hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % len)
+  }
+
+  fn insert(&mut self, key: u64) {
+    for bit in self.positions(key).collect::<Vec<u64>>() {
+      self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+    }
+  }
+
+  fn may_contain(&self, key: u64) -> bool {
+    self.positions(key).all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+  }
+}
+
+/// splitmix64 finalizer
+fn mix(x: u64) -> u64 {
+  let x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+  let x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+  let x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+  x ^ (x >> 31)
+}
+
+/// the indexes of one block, checked against its checksum
+fn decode_block(buf: &[u8], count: u64) -> io::Result<Vec<IndexFileItem>> {
+  let (items, checksum) = buf.split_at(BLOCK_SIZE as usize - 4);
+  if crc32c::update(0, items) != u32::decode(checksum)? {
+    return Err(corrupted("index file block checksum mismatch".to_string()));
+  }
+  items.chunks(IndexFileItem::SIZE)
+    .take(count as usize)
+    .map(IndexFileItem::decode)
+    .collect()
+}
+
+#[derive(Debug)]
+pub struct SortedIndex {
+  path: String,
+  f: fs::File,
+  watermarks: Vec<Watermark>,
+  count: u64,                                  // number of indexes
+  first_keys: Vec<u64>,                        // the block index
+  bloom: Bloom,
+  max_blocks: usize,
+  blocks: HashMap<u64, (Vec<IndexFileItem>, u64)>, // block number -> (indexes, last use)
+  lru: BTreeMap<u64, u64>,                     // last use -> block number
+  tick: u64
+}
+
+impl SortedIndex {
+  /// open the index file at path, an empty file holds no indexes and no watermarks
+  /// only the block index, the bloom filter and the watermarks are read
+  pub fn open(path: &str, max_blocks: usize) -> io::Result<Self> {
+    let mut f = fs::File::open(path)?;
+    let len = f.metadata()?.len();
+    let mut sorted = SortedIndex {
+      path: path.to_string(),
+      f: f.try_clone()?,
+      watermarks: vec![],
+      count: 0,
+      first_keys: vec![],
+      bloom: Bloom::new(0),
+      max_blocks: max_blocks.max(1),
+      blocks: HashMap::new(),
+      lru: BTreeMap::new(),
+      tick: 0
+    };
+    if len == 0 {
+      return Ok(sorted);
+    }
+
+    let truncated = || corrupted(format!("{} is truncated", path));
+    if len < Footer::SIZE as u64 {
+      return Err(truncated());
+    }
+    f.seek(io::SeekFrom::Start(len - Footer::SIZE as u64))?;
+    let footer_bytes = read_write::read_bytes_from_file(Footer::SIZE as u64, &mut f)?;
+    let footer = Footer::decode(&footer_bytes)?;
+    if footer.count > footer.blocks * BLOCK_ITEMS
+      || footer.blocks * BLOCK_SIZE + footer.meta_size() + Footer::SIZE as u64 != len {
+      return Err(truncated());
+    }
+
+    f.seek(io::SeekFrom::Start(footer.blocks * BLOCK_SIZE))?;
+    let meta = read_write::read_bytes_from_file(footer.meta_size(), &mut f)?;
+    let checksum = crc32c::update(crc32c::update(0, &meta), &footer_bytes[..Footer::SIZE - 4]);
+    if checksum != footer.checksum {
+      return Err(corrupted(format!("{} checksum mismatch", path)));
+    }
+
+    let mut reader = Reader::new(&meta);
+    for _ in 0..footer.blocks {
+      sorted.first_keys.push(reader.read()?);
+    }
+    sorted.bloom.bits.clear();
+    for _ in 0..footer.bloom_words {
+      sorted.bloom.bits.push(reader.read()?);
+    }
+    sorted.bloom.hashes = footer.bloom_hashes;
+    for _ in 0..footer.watermarks {
+      sorted.watermarks.push(reader.read()?);
+    }
+    sorted.count = footer.count;
+    Ok(sorted)
+  }
+
+  /// an index file without indexes that is never written, for tests of the in-memory index
+  #[cfg(test)]
+  pub fn detached() -> Self {
+    SortedIndex::open("/dev/null", 1).unwrap()
+  }
+
+  /// write indexes, ordered by key and generation, into a new index file at path
+  /// expected is about the number of indexes, it sizes the bloom filter
+  /// return the number of indexes written
+  pub fn write(
+    path: &str,
+    watermarks: &[Watermark],
+    expected: u64,
+    indexes: impl Iterator<Item = io::Result<IndexFileItem>>
+  ) -> io::Result<u64> {
+    let mut w = io::BufWriter::new(fs::File::create(path)?);
+    let mut bloom = Bloom::new(expected);
+    let mut first_keys = vec![];
+    let mut count = 0;
+    let mut block = Vec::with_capacity(BLOCK_SIZE as usize);
+    let mut indexes = indexes.peekable();
+    while indexes.peek().is_some() {
+      block.clear();
+      for index in indexes.by_ref().take(BLOCK_ITEMS as usize) {
+        let index = index?;
+        if block.is_empty() {
+          first_keys.push(index.key);
+        }
+        bloom.insert(index.key);
+        index.encode(&mut block);
+        count += 1;
+      }
+      block.resize(BLOCK_SIZE as usize - 4, 0);
+      crc32c::update(0, &block).encode(&mut block);
+      w.write_all(&block)?;
+    }
+
+    let mut meta = vec![];
+    for key in &first_keys {
+      key.encode(&mut meta);
+    }
+    for word in &bloom.bits {
+      word.encode(&mut meta);
+    }
+    for watermark in watermarks {
+      watermark.encode(&mut meta);
+    }
+    let mut footer = Footer {
+      count,
+      blocks: first_keys.len() as u64,
+      bloom_words: bloom.bits.len() as u64,
+      bloom_hashes: bloom.hashes,
+      watermarks: watermarks.len() as u32,
+      checksum: 0
+    };
+    let footer_bytes = footer.to_bytes();
+    footer.checksum = crc32c::update(crc32c::update(0, &meta), &footer_bytes[..Footer::SIZE - 4]);
+    footer.encode(&mut meta);
+    w.write_all(&meta)?;
+    let f = w.into_inner().map_err(|e| e.into_error())?;
+    metrics::FSYNC.time(|| f.sync_all())?;
+
+    Ok(count)
+  }
+
+  pub fn watermarks(&self) -> &[Watermark] {
+    &self.watermarks
+  }
+
+  pub fn max_blocks(&self) -> usize {
+    self.max_blocks
+  }
+
+  pub fn count(&self) -> u64 {
+    self.count
+  }
+
+  /// number of blocks in memory
+  #[cfg(test)]
+  pub fn cached_blocks(&self) -> usize {
+    self.blocks.len()
+  }
+
+  /// number of indexes in the blocks in memory
+  pub fn cached_indexes(&self) -> usize {
+    self.blocks.values().map(|(block, _)| block.len()).sum()
+  }
+
+  /// the index of the latest generation of key: the bloom filter rules out most missing keys,
+  /// the block index tells the only block that may hold it
+  pub fn get(&mut self, key: u64) -> io::Result<Option<IndexFileItem>> {
+    if !self.bloom.may_contain(key) {
+      return Ok(None);
+    }
+    let n = match self.first_keys.partition_point(|first| *first <= key) {
+      0 => return Ok(None),
+      n => n as u64 - 1
+    };
+    let block = self.block(n)?;
+    let end = block.partition_point(|index| index.key <= key);
+    Ok(block[..end].last().filter(|index| index.key == key).cloned())
+  }
+
+  /// the indexes of every generation of key, the oldest first
+  pub fn versions(&mut self, key: u64) -> io::Result<Vec<IndexFileItem>> {
+    let mut r = vec![];
+    if !self.bloom.may_contain(key) {
+      return Ok(r);
+    }
+    // the generations of key may start in the block before the first one beginning with key
+    let first = self.first_keys.partition_point(|first| *first < key).saturating_sub(1);
+    let last = self.first_keys.partition_point(|first| *first <= key);
+    for n in first..last {
+      r.extend(self.block(n as u64)?.iter().filter(|index| index.key == key).cloned());
+    }
+    Ok(r)
+  }
+
+  /// the key of the last index
+  pub fn last_key(&mut self) -> io::Result<Option<u64>> {
+    if self.first_keys.is_empty() {
+      return Ok(None);
+    }
+    let block = self.block(self.first_keys.len() as u64 - 1)?;
+    Ok(block.last().map(|index| index.key))
+  }
+
+  /// the indexes of block n, read from the disk unless they are in memory
+  fn block(&mut self, n: u64) -> io::Result<&[IndexFileItem]> {
+    self.tick += 1;
+    if let Some((_, last_use)) = self.blocks.get_mut(&n) {
+      self.lru.remove(last_use);
+      *last_use = self.tick;
+      self.lru.insert(self.tick, n);
+      return Ok(&self.blocks[&n].0);
+    }
+
+    if self.blocks.len() >= self.max_blocks {
+      if let Some((_, evicted)) = self.lru.pop_first() {
+        self.blocks.remove(&evicted);
+      }
+    }
+    self.f.seek(io::SeekFrom::Start(n * BLOCK_SIZE))?;
+    let bytes = read_write::read_bytes_from_file(BLOCK_SIZE, &mut self.f)?;
+    let block = decode_block(&bytes, BLOCK_ITEMS.min(self.count - n * BLOCK_ITEMS))?;
+    self.lru.insert(self.tick, n);
+    Ok(&self.blocks.entry(n).or_insert((block, self.tick)).0)
+  }
+
+  /// all indexes in order, read through a separate handle without touching the blocks in memory
+  pub fn iter(&self) -> io::Result<Iter> {
+    self.iter_from(0)
+  }
+
+  /// the indexes in order from the block that may hold the first generation of key on,
+  /// the ones of smaller keys in that block are not skipped
+  pub fn iter_from(&self, key: u64) -> io::Result<Iter> {
+    let n = self.first_keys.partition_point(|first| *first < key).saturating_sub(1) as u64;
+    let mut r = io::BufReader::new(fs::File::open(&self.path)?);
+    r.seek(io::SeekFrom::Start(n * BLOCK_SIZE))?;
+    Ok(Iter {
+      r,
+      block: vec![].into_iter(),
+      left: self.count.saturating_sub(n * BLOCK_ITEMS)
+    })
+  }
+}
+
+pub struct Iter {
+  r: io::BufReader<fs::File>,
+  block: std::vec::IntoIter<IndexFileItem>,
+  left: u64          // indexes in the blocks not read yet
+}
+
+impl Iterator for Iter {
+  type Item = io::Result<IndexFileItem>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if let Some(index) = self.block.next() {
+      return Some(Ok(index));
+    }
+    if self.left == 0 {
+      return None;
+    }
+    let mut buf = vec![0u8; BLOCK_SIZE as usize];
+    let count = BLOCK_ITEMS.min(self.left);
+    self.left -= count;
+    match self.r.read_exact(&mut buf).and_then(|_| decode_block(&buf, count)) {
+      Ok(block) => {
+        self.block = block.into_iter();
+        self.block.next().map(Ok)
+      },
+      Err(e) => {
+        self.left = 0;
+        Some(Err(e))
+      }
+    }
+  }
+}
+
+/// the indexes of the index file and of newer ones, both ordered by key and generation
+/// for the same key and generation the newer index wins
+pub struct Merged<'a, I: Iterator<Item = &'a IndexFileItem>> {
+  disk: Peekable<Iter>,
+  newer: Peekable<I>
+}
+
+pub fn merge<'a, I: Iterator<Item = &'a IndexFileItem>>(disk: Iter, newer: I) -> Merged<'a, I> {
+  Merged {
+    disk: disk.peekable(),
+    newer: newer.peekable()
+  }
+}
+
+impl<'a, I: Iterator<Item = &'a IndexFileItem>> Iterator for Merged<'a, I> {
+  type Item = io::Result<IndexFileItem>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let disk_id = match self.disk.peek() {
+      Some(Ok(index)) => Some(index.id()),
+      Some(Err(_)) => return self.disk.next(),
+      None => None
+    };
+    match (disk_id, self.newer.peek().map(|index| index.id())) {
+      (Some(d), Some(n)) if d < n => self.disk.next(),
+      (Some(d), Some(n)) => {
+        if d == n {
+          self.disk.next();
+        }
+        self.newer.next().cloned().map(Ok)
+      },
+      (Some(_), None) => self.disk.next(),
+      (None, Some(_)) => self.newer.next().cloned().map(Ok),
+      (None, None) => None
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn item(key: u64) -> IndexFileItem {
+    IndexFileItem { cookie: key as u32, key, generation: 1, flag: true, volume: 0, offset: 64 + key * 40, size: key }
+  }
+
+  #[test]
+  fn footer_fixture() {
+    let footer = Footer { count: 3, blocks: 1, bloom_words: 2, bloom_hashes: 7, watermarks: 1, checksum: 0x01020304 };
+    let bytes = vec![
+      b'H', b'I', b'D', b'X',
+      2, 0, 0, 0,
+      3, 0, 0, 0, 0, 0, 0, 0,
+      1, 0, 0, 0, 0, 0, 0, 0,
+      2, 0, 0, 0, 0, 0, 0, 0,
+      7, 0, 0, 0,
+      1, 0, 0, 0,
+      0x04, 0x03, 0x02, 0x01
+    ];
+    assert_eq!(footer.to_bytes(), bytes);
+    assert_eq!(Footer::decode(&bytes).unwrap(), footer);
+    assert_eq!(BLOCK_ITEMS, 110);
+  }
+
+  #[test]
+  fn search_with_bounded_blocks() -> io::Result<()> {
+    let path = "test_sorted_index";
+    let watermarks = vec![Watermark { volume: 0, offset: 1000 }, Watermark { volume: 1, offset: 64 }];
+    // only even keys
+    let count = SortedIndex::write(path, &watermarks, 1000, (0..1000).map(|i| Ok(item(i * 2))))?;
+    assert_eq!(count, 1000);
+
+    let mut sorted = SortedIndex::open(path, 3)?;
+    assert_eq!(sorted.count(), 1000);
+    assert_eq!(sorted.watermarks(), &watermarks[..]);
+    for key in 0..2000 {
+      let found = sorted.get(key)?.map(|index| index.offset);
+      assert_eq!(found, if key % 2 == 0 { Some(64 + key * 40) } else { None });
+      assert!(sorted.cached_blocks() <= 3);
+    }
+    assert!(sorted.get(5000)?.is_none());
+    assert_eq!(sorted.last_key()?, Some(1998));
+
+    let keys: Vec<u64> = sorted.iter()?.map(|index| index.map(|index| index.key)).collect::<io::Result<_>>()?;
+    assert_eq!(keys, (0..1000).map(|i| i * 2).collect::<Vec<u64>>());
+
+    fs::remove_file(path)?;
+    Ok(())
+  }
+
+  #[test]
+  fn generations_across_blocks() -> io::Result<()> {
+    let path = "test_sorted_generations";
+    // 7 generations of every key, so the generations of some keys span two blocks
+    let indexes = (0..100).flat_map(|key| (1..=7).map(move |generation| IndexFileItem { generation, ..item(key) }));
+    SortedIndex::write(path, &[], 700, indexes.map(Ok))?;
+
+    let mut sorted = SortedIndex::open(path, 1)?;
+    for key in 0..100 {
+      assert_eq!(sorted.get(key)?.map(|index| (index.key, index.generation)), Some((key, 7)));
+      let generations: Vec<u32> = sorted.versions(key)?.iter().map(|index| index.generation).collect();
+      assert_eq!(generations, (1..=7).collect::<Vec<u32>>());
+    }
+    assert!(sorted.versions(100)?.is_empty());
+
+    fs::remove_file(path)?;
+    Ok(())
+  }
+
+  #[test]
+  fn bloom_rules_out_missing_keys() {
+    let mut bloom = Bloom::new(10_000);
+    for key in 0..10_000 {
+      bloom.insert(key * 3);
+    }
+    assert!((0..10_000).all(|key| bloom.may_contain(key * 3)));
+    let false_positives = (0..10_000).filter(|key| bloom.may_contain(key * 3 + 1)).count();
+    assert!(false_positives < 300, "{} false positives", false_positives);
+  }
+
+  #[test]
+  fn corrupted_index_file_is_detected() -> io::Result<()> {
+    let path = "test_sorted_corrupted";
+    SortedIndex::write(path, &[], 300, (0..300).map(|key| Ok(item(key))))?;
+
+    // a flipped bit in the second block
+    let mut f = fs::OpenOptions::new().read(true).write(true).open(path)?;
+    f.seek(io::SeekFrom::Start(BLOCK_SIZE + 10))?;
+    f.write_all(&[0xff])?;
+    let mut sorted = SortedIndex::open(path, 4)?;
+    assert_eq!(sorted.get(1)?.map(|index| index.key), Some(1));
+    assert!(super::super::is_corrupted(&sorted.get(BLOCK_ITEMS + 1).unwrap_err()));
+    assert!(sorted.iter()?.any(|index| index.is_err()));
+
+    // a flipped bit in the block index
+    f.seek(io::SeekFrom::Start(3 * BLOCK_SIZE))?;
+    f.write_all(&[0xff])?;
+    assert!(super::super::is_corrupted(&SortedIndex::open(path, 4).unwrap_err()));
+
+    // a torn index file
+    f.set_len(3 * BLOCK_SIZE)?;
+    assert!(super::super::is_corrupted(&SortedIndex::open(path, 4).unwrap_err()));
+
+    fs::remove_file(path)?;
+    Ok(())
+  }
+
+  #[test]
+  fn empty_index_file() -> io::Result<()> {
+    let mut sorted = SortedIndex::detached();
+    assert_eq!(sorted.count(), 0);
+    assert!(sorted.watermarks().is_empty());
+    assert!(sorted.get(0)?.is_none());
+    assert_eq!(sorted.last_key()?, None);
+
+    // no blocks, but still a footer with the watermarks
+    let path = "test_sorted_empty";
+    SortedIndex::write(path, &[Watermark { volume: 0, offset: 64 }], 0, vec![].into_iter())?;
+    let mut sorted = SortedIndex::open(path, 1)?;
+    assert_eq!(sorted.count(), 0);
+    assert_eq!(sorted.watermarks().len(), 1);
+    assert!(sorted.get(0)?.is_none());
+
+    fs::remove_file(path)?;
+    Ok(())
+  }
+
+  #[test]
+  fn newer_indexes_win() -> io::Result<()> {
+    let path = "test_sorted_merge";
+    SortedIndex::write(path, &[], 3, vec![1, 3, 5].into_iter().map(|key| Ok(item(key))))?;
+    let sorted = SortedIndex::open(path, 1)?;
+
+    let mut deleted = item(3);
+    deleted.flag = false;
+    let updated = IndexFileItem { generation: 2, ..item(5) };
+    let newer = [item(0), deleted, item(4), updated, item(9)];
+    let merged = merge(sorted.iter()?, newer.iter())
+      .map(|index| index.map(|index| (index.key, index.generation, index.flag)))
+      .collect::<io::Result<Vec<(u64, u32, bool)>>>()?;
+    assert_eq!(merged, vec![
+      (0, 1, true), (1, 1, true), (3, 1, false), (4, 1, true), (5, 1, true), (5, 2, true), (9, 1, true)
+    ]);
+
+    fs::remove_file(path)?;
+    Ok(())
+  }
+}
@@ -0,0 +1,253 @@
+//! the superblock at the beginning of every physical volume
+//! and the set of volumes a store is made of
+
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::diskio::codec::{Codec, Reader};
+use crate::diskio::read_write;
+
+/// "HEYSTACK" in ascii
+pub const VOLUME_MAGIC: u64 = 0x4b43_4154_5359_4548;
+/// bumped whenever the layout of the volume or needles changes
+pub const FORMAT_VERSION: u32 = 7;
+
+/// layout: magic u64 | version u32 | volume_id u32 | created u64 | reserved, all zero
+#[derive(Debug, Clone, PartialEq)]
+pub struct Superblock {
+  pub version: u32,
+  pub volume_id: u32,
+  pub created: u64,   // unix timestamp in seconds
+}
+
+impl Codec for Superblock {
+  const SIZE: usize = 64;
+
+  fn encode(&self, buf: &mut Vec<u8>) {
+    let start = buf.len();
+    VOLUME_MAGIC.encode(buf);
+    self.version.encode(buf);
+    self.volume_id.encode(buf);
+    self.created.encode(buf);
+    buf.resize(start + Self::SIZE, 0);
+  }
+
+  fn decode(buf: &[u8]) -> io::Result<Self> {
+    let mut reader = Reader::new(buf);
+    if reader.read::<u64>()? != VOLUME_MAGIC {
+      return Err(super::corrupted("not a heystack volume".to_string()));
+    }
+    let version = reader.read()?;
+    if version != FORMAT_VERSION {
+      return Err(super::corrupted(format!("unsupported volume format version {}", version)));
+    }
+    Ok(Superblock {
+      version,
+      volume_id: reader.read()?,
+      created: reader.read()?
+    })
+  }
+}
+
+impl Superblock {
+  /// read and check the superblock, f will be positioned at the first needle
+  pub fn read_from(f: &mut fs::File) -> io::Result<Self> {
+    f.seek(io::SeekFrom::Start(0))?;
+    match read_write::read_struct_from_file(f) {
+      Ok(Some(superblock)) => Ok(superblock),
+      Ok(None) => Err(super::corrupted("volume has no superblock".to_string())),
+      Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(super::corrupted("volume has no superblock".to_string())),
+      Err(e) => Err(e)
+    }
+  }
+
+  /// write a superblock into the volume at path if it is empty
+  /// return the superblock of the volume
+  pub fn create_if_empty(path: &str, volume_id: u32) -> io::Result<Self> {
+    let mut f = fs::OpenOptions::new()
+      .read(true)
+      .write(true)
+      .open(path)?;
+    if f.metadata()?.len() == 0 {
+      let superblock = Superblock {
+        version: FORMAT_VERSION,
+        volume_id,
+        created: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+      };
+      read_write::append_struct_to_file(&superblock, &mut f)?;
+      f.sync_all()?;
+    }
+
+    Superblock::read_from(&mut f)
+  }
+}
+
+/// all volumes of a store, named "{prefix}.{volume_id}"
+/// new needles are appended to the last volume only, the others are read-only
+#[derive(Debug, Clone)]
+pub struct VolumeSet {
+  prefix: String,
+  max_size: u64,      // a volume is read-only once it would grow beyond max_size
+  ids: Vec<u32>       // sorted, the last one is writable
+}
+
+impl VolumeSet {
+  /// find the volumes with the given prefix, create the first one if there is none
+  /// a single volume named prefix, of a store before volume sets, is refused: its needles would be lost
+  pub fn open(prefix: &str, max_size: u64) -> io::Result<Self> {
+    let path = Path::new(prefix);
+    if path.exists() {
+      return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+        format!("{} is a volume of an older version, rename it to {}.0 and reload", prefix, prefix)));
+    }
+    let dir = match path.parent() {
+      Some(dir) if dir != Path::new("") => dir,
+      _ => Path::new(".")
+    };
+    let name = path.file_name()
+      .and_then(|name| name.to_str())
+      .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "bad volume name"))?;
+
+    let mut ids = vec![];
+    for entry in fs::read_dir(dir)? {
+      let entry = entry?;
+      let filename = entry.file_name();
+      if let Some(id) = filename.to_str()
+        .and_then(|f| f.strip_prefix(name))
+        .and_then(|f| f.strip_prefix('.'))
+        .and_then(|id| id.parse::<u32>().ok()) {
+        ids.push(id);
+      }
+    }
+    ids.sort_unstable();
+
+    let mut volumes = VolumeSet {
+      prefix: prefix.to_string(),
+      max_size,
+      ids
+    };
+    if volumes.ids.is_empty() {
+      volumes.create(0)?;
+    }
+    Ok(volumes)
+  }
+
+  /// a volume set that is never touched on disk, for tests of the in-memory index
+  #[cfg(test)]
+  pub fn detached() -> Self {
+    VolumeSet {
+      prefix: String::new(),
+      max_size: u64::MAX,
+      ids: vec![0]
+    }
+  }
+
+  fn create(&mut self, volume_id: u32) -> io::Result<()> {
+    let path = self.path(volume_id);
+    fs::OpenOptions::new()
+      .write(true)
+      .create_new(true)
+      .open(&path)?;
+    Superblock::create_if_empty(&path, volume_id)?;
+    crate::logln!("Create volume ", path);
+    self.ids.push(volume_id);
+    Ok(())
+  }
+
+  pub fn path(&self, volume_id: u32) -> String {
+    format!("{}.{}", self.prefix, volume_id)
+  }
+
+  pub fn ids(&self) -> &[u32] {
+    &self.ids
+  }
+
+  pub fn writable(&self) -> u32 {
+    *self.ids.last().unwrap()
+  }
+
+  pub fn max_size(&self) -> u64 {
+    self.max_size
+  }
+
+  /// the volume that can take another size bytes
+  /// the writable volume becomes read-only and a new one is created if it is full
+  pub fn writable_for(&mut self, size: u64) -> io::Result<u32> {
+    let current = self.writable();
+    let len = fs::metadata(self.path(current))?.len();
+    // an empty volume takes the needle even if it is larger than max_size
+    if len > Superblock::SIZE as u64 && len.saturating_add(size) > self.max_size {
+      crate::logln!("Volume ", current, " is full, size: ", len);
+      self.create(current + 1)?;
+    }
+    Ok(self.writable())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn superblock_fixture() {
+    let superblock = Superblock { version: FORMAT_VERSION, volume_id: 3, created: 0x5f5e_1000 };
+    let mut bytes = vec![
+      b'H', b'E', b'Y', b'S', b'T', b'A', b'C', b'K',
+      FORMAT_VERSION as u8, 0, 0, 0,
+      3, 0, 0, 0,
+      0x00, 0x10, 0x5e, 0x5f, 0, 0, 0, 0
+    ];
+    bytes.resize(64, 0);
+    assert_eq!(superblock.to_bytes(), bytes);
+    assert_eq!(Superblock::decode(&bytes).unwrap(), superblock);
+
+    bytes[0] = b'h';
+    assert!(super::super::is_corrupted(&Superblock::decode(&bytes).unwrap_err()));
+  }
+
+  #[test]
+  fn create_once() -> io::Result<()> {
+    let filename = "test_superblock";
+    fs::File::create(filename)?;
+    let a = Superblock::create_if_empty(filename, 7)?;
+    let b = Superblock::create_if_empty(filename, 8)?;
+    assert_eq!(a, b);
+    assert_eq!(b.volume_id, 7);
+    assert_eq!(fs::metadata(filename)?.len(), Superblock::SIZE as u64);
+
+    fs::remove_file(filename)?;
+    Ok(())
+  }
+
+  #[test]
+  fn roll_over_when_full() -> io::Result<()> {
+    let dir = "test_volume_set";
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir)?;
+    let prefix = format!("{}/v", dir);
+    let mut volumes = VolumeSet::open(&prefix, 1000)?;
+    assert_eq!(volumes.ids(), &[0]);
+
+    // empty volumes take any needle
+    assert_eq!(volumes.writable_for(5000)?, 0);
+    fs::OpenOptions::new().append(true).open(volumes.path(0))?.write_all(&[0u8; 500])?;
+    assert_eq!(volumes.writable_for(400)?, 0);
+    assert_eq!(volumes.writable_for(500)?, 1);
+    assert_eq!(Superblock::read_from(&mut fs::File::open(volumes.path(1))?)?.volume_id, 1);
+
+    // not a volume
+    fs::File::create(format!("{}.1.compact", prefix))?;
+    let volumes = VolumeSet::open(&prefix, 1000)?;
+    assert_eq!(volumes.ids(), &[0, 1]);
+
+    // the volume of a store before volume sets
+    fs::File::create(&prefix)?;
+    assert_eq!(VolumeSet::open(&prefix, 1000).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+
+    fs::remove_dir_all(dir)?;
+    Ok(())
+  }
+}